@@ -15,15 +15,12 @@ async fn main() -> anyhow::Result<()> {
     let connection_num = 100;
     for _ in 0..connection_num {
         let req = Request::get(Uri::from_static("http://www.baidu.com")).body(Bytes::new())?;
-        client.request(req).unwrap();
+        client.request(req, ()).unwrap();
     }
     for _ in 0..connection_num {
-        let (_handle, _response) = futures::future::poll_fn(|cx| {
-            cx.waker().wake_by_ref();
-            client.poll_response(cx)
-        })
-        .await
-        .unwrap()?;
+        let (_handle, _response) = futures::future::poll_fn(|cx| client.poll_response(cx))
+            .await
+            .unwrap()?;
         // let body = req.into_body();
         // println!("Read {} bytes", body.len());
     }
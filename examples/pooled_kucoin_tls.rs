@@ -7,7 +7,6 @@ use speedy_http::{HttpClientPool, HttpClientPoolConfig};
 use std::io::Write;
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
-use std::task::Poll;
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio_rustls::TlsConnector;
@@ -49,28 +48,25 @@ async fn main() -> anyhow::Result<()> {
         HttpClientPoolConfig {
             maintain_size: Some(100),
             max_conv_per_channel: 10,
+            ..Default::default()
         },
     );
     let connection_num = 100;
-    client.poll_maintain_connection();
-    for _ in 0..500 {
-        tokio::time::sleep(Duration::from_millis(1)).await;
-        futures::future::poll_fn(|cx| Poll::Ready(client.poll_connecting(cx))).await;
-    }
+    let readiness = client.wait_connected(Duration::from_secs(5)).await;
+    info!(
+        "{} connections ready, {} attempts failed",
+        readiness.connected, readiness.failed_attempts
+    );
     let begin = std::time::Instant::now();
     for _ in 0..connection_num {
         let req =
             Request::get(Uri::from_static("https://api.kucoin.com/timestamp")).body(Bytes::new())?;
-        client.request(req, std::time::Instant::now());
+        client.request(req, std::time::Instant::now())?;
         client.poll_send_request();
     }
     let mut sum_time = 0;
     for _ in 0..connection_num {
-        let (handle, _response) = futures::future::poll_fn(|cx| {
-            cx.waker().wake_by_ref();
-            client.poll_response(cx)
-        })
-        .await;
+        let (handle, _response) = futures::future::poll_fn(|cx| client.poll_response(cx)).await;
         sum_time += handle.into_data().elapsed().as_micros();
     }
     let elapsed = begin.elapsed();
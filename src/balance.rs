@@ -0,0 +1,298 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What a `Balancer` sees of a connection that takes requests.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub client_id: usize,
+    /// Requests written to the connection and still waiting for their response.
+    pub queue_len: usize,
+    /// Whether the connection is below `max_conv_per_channel`. Connections that cannot take the
+    /// request at all, such as a GET while HEAD requests are in flight, are not offered.
+    pub has_slot: bool,
+    pub age: Duration,
+    /// Moving average of the connection's response latency, unknown until a response arrived.
+    pub latency: Option<Duration>,
+}
+
+/// Picks the connection for the next request.
+pub trait Balancer: Send {
+    /// Returns the position in `connections` to send to, or `None` to keep the request
+    /// pending. `connections` is never empty. Connections without a free slot may still be
+    /// picked, in which case the request is pipelined behind the others.
+    fn select(&mut self, connections: &[ConnectionInfo]) -> Option<usize>;
+}
+
+/// Which `Balancer` a pool uses.
+#[derive(Clone, Default)]
+pub enum BalanceStrategy {
+    /// Cycles through the connections with a free slot, falling back to the shortest queue.
+    #[default]
+    RoundRobin,
+    /// The connection with the fewest requests in flight.
+    LeastOutstanding,
+    /// The less loaded of two connections picked at random.
+    PowerOfTwoChoices,
+    /// The connection with the lowest latency, weighted by its queue length.
+    LatencyEwma,
+    Custom(Arc<dyn Fn() -> Box<dyn Balancer> + Send + Sync>),
+}
+impl BalanceStrategy {
+    pub fn build(&self) -> Box<dyn Balancer> {
+        match self {
+            BalanceStrategy::RoundRobin => Box::new(RoundRobin::default()),
+            BalanceStrategy::LeastOutstanding => Box::new(LeastOutstanding),
+            BalanceStrategy::PowerOfTwoChoices => Box::new(PowerOfTwoChoices::default()),
+            BalanceStrategy::LatencyEwma => Box::new(LatencyEwma),
+            BalanceStrategy::Custom(factory) => factory(),
+        }
+    }
+}
+
+fn least_outstanding(connections: &[ConnectionInfo]) -> Option<usize> {
+    connections
+        .iter()
+        .enumerate()
+        .min_by_key(|x| (!x.1.has_slot, x.1.queue_len))
+        .map(|x| x.0)
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+impl Balancer for RoundRobin {
+    fn select(&mut self, connections: &[ConnectionInfo]) -> Option<usize> {
+        let len = connections.len();
+        for i in 0..len {
+            let id = (self.next + i) % len;
+            if connections[id].has_slot {
+                self.next = id + 1;
+                return Some(id);
+            }
+        }
+        least_outstanding(connections)
+    }
+}
+
+pub struct LeastOutstanding;
+impl Balancer for LeastOutstanding {
+    fn select(&mut self, connections: &[ConnectionInfo]) -> Option<usize> {
+        least_outstanding(connections)
+    }
+}
+
+#[derive(Default)]
+pub struct PowerOfTwoChoices {
+    rng: Rng,
+}
+impl Balancer for PowerOfTwoChoices {
+    fn select(&mut self, connections: &[ConnectionInfo]) -> Option<usize> {
+        let len = connections.len();
+        if len < 2 {
+            return least_outstanding(connections);
+        }
+        let a = self.rng.below(len);
+        let b = (a + 1 + self.rng.below(len - 1)) % len;
+        let load = |x: &ConnectionInfo| (!x.has_slot, x.queue_len);
+        if load(&connections[b]) < load(&connections[a]) {
+            Some(b)
+        } else {
+            Some(a)
+        }
+    }
+}
+
+pub struct LatencyEwma;
+impl Balancer for LatencyEwma {
+    fn select(&mut self, connections: &[ConnectionInfo]) -> Option<usize> {
+        // Connections without a sample yet score zero, so every new connection gets probed.
+        let cost = |x: &ConnectionInfo| {
+            x.latency.unwrap_or_default().as_nanos() as u64 * (x.queue_len as u64 + 1)
+        };
+        connections
+            .iter()
+            .enumerate()
+            .min_by_key(|x| (!x.1.has_slot, cost(x.1)))
+            .map(|x| x.0)
+    }
+}
+
+/// Xorshift generator, good enough to keep clients from acting in lockstep.
+pub(crate) struct Rng(u64);
+impl Default for Rng {
+    fn default() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |x| x.as_nanos() as u64);
+        Rng(seed | 1)
+    }
+}
+impl Rng {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    /// Uniform in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+    /// Uniform in `[0, 1)`.
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn(queue_len: usize, has_slot: bool, latency_ms: Option<u64>) -> ConnectionInfo {
+        ConnectionInfo {
+            client_id: 0,
+            queue_len,
+            has_slot,
+            age: Duration::ZERO,
+            latency: latency_ms.map(Duration::from_millis),
+        }
+    }
+
+    #[test]
+    fn least_outstanding_selection() {
+        let cases = vec![
+            (
+                "shortest queue",
+                vec![conn(3, true, None), conn(1, true, None)],
+                1,
+            ),
+            (
+                "tie goes to the first",
+                vec![conn(1, true, None), conn(1, true, None)],
+                0,
+            ),
+            (
+                "free slot beats shorter queue",
+                vec![conn(0, false, None), conn(5, true, None)],
+                1,
+            ),
+            (
+                "all full, shortest queue",
+                vec![conn(4, false, None), conn(2, false, None)],
+                1,
+            ),
+            ("single connection", vec![conn(9, false, None)], 0),
+        ];
+        for (name, connections, expected) in cases {
+            assert_eq!(
+                LeastOutstanding.select(&connections),
+                Some(expected),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn latency_ewma_selection() {
+        let cases = vec![
+            (
+                "lowest latency",
+                vec![conn(0, true, Some(10)), conn(0, true, Some(5))],
+                1,
+            ),
+            // 10ms * 2 against 5ms * 5.
+            (
+                "weighted by queue",
+                vec![conn(1, true, Some(10)), conn(4, true, Some(5))],
+                0,
+            ),
+            (
+                "unmeasured is probed",
+                vec![conn(0, true, Some(1)), conn(3, true, None)],
+                1,
+            ),
+            (
+                "tie goes to the first",
+                vec![conn(1, true, Some(5)), conn(1, true, Some(5))],
+                0,
+            ),
+            (
+                "free slot beats lower cost",
+                vec![conn(0, false, Some(1)), conn(0, true, Some(50))],
+                1,
+            ),
+        ];
+        for (name, connections, expected) in cases {
+            assert_eq!(LatencyEwma.select(&connections), Some(expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn round_robin_selection() {
+        let cases = vec![
+            ("cycles", vec![conn(0, true, None); 3], vec![0, 1, 2, 0]),
+            (
+                "skips full connections",
+                vec![
+                    conn(0, true, None),
+                    conn(0, false, None),
+                    conn(0, true, None),
+                ],
+                vec![0, 2, 0, 2],
+            ),
+            (
+                "all full, shortest queue",
+                vec![
+                    conn(3, false, None),
+                    conn(1, false, None),
+                    conn(2, false, None),
+                ],
+                vec![1, 1],
+            ),
+        ];
+        for (name, connections, expected) in cases {
+            let mut balancer = RoundRobin::default();
+            let picked: Vec<_> = expected
+                .iter()
+                .map(|_| balancer.select(&connections).unwrap())
+                .collect();
+            assert_eq!(picked, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn power_of_two_choices_selection() {
+        // The choice is random, so each case lists every acceptable pick.
+        let cases = vec![
+            ("single connection", vec![conn(5, false, None)], vec![0]),
+            (
+                "less loaded of two",
+                vec![conn(3, true, None), conn(1, true, None)],
+                vec![1],
+            ),
+            (
+                "free slot of two",
+                vec![conn(0, false, None), conn(7, true, None)],
+                vec![1],
+            ),
+            (
+                "most loaded never wins",
+                vec![
+                    conn(0, true, None),
+                    conn(0, true, None),
+                    conn(9, true, None),
+                ],
+                vec![0, 1],
+            ),
+        ];
+        for (name, connections, allowed) in cases {
+            let mut balancer = PowerOfTwoChoices::default();
+            for _ in 0..100 {
+                let picked = balancer.select(&connections).unwrap();
+                assert!(allowed.contains(&picked), "{}: picked {}", name, picked);
+            }
+        }
+    }
+}
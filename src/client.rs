@@ -1,162 +1,1101 @@
-use crate::ensure;
-use crate::RequestHandle;
+use crate::timer::Timer;
+use crate::{Error, Rejected, RequestHandle};
 use bytes::{Bytes, BytesMut};
-use http::header::HOST;
-use http::{HeaderValue, Request, Response};
+use futures::Stream;
+use http::header::{CONNECTION, HOST};
+use http::uri::Authority;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
 use hyper::body::{Buf, DecodedLength};
 use hyper::proto::h1::ClientTransaction;
 use hyper::proto::{Conn, RequestHead, RequestLine};
-use std::io::ErrorKind;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::*;
+
+/// How far the requests on a connection got. Offsets count bytes from the start of the
+/// connection.
+#[derive(Default)]
+pub(crate) struct WriteProgress {
+    /// Bytes hyper encoded into the connection so far.
+    encoded: AtomicU64,
+    /// Bytes the socket accepted so far.
+    accepted: AtomicU64,
+    /// While set, flushing only moves hyper's write buffer into `CountWritten`, so that
+    /// `encoded` covers every request buffered so far.
+    hold: AtomicBool,
+}
+
+/// Takes over hyper's write buffer and counts the bytes the socket accepted, so that a broken
+/// connection can tell which requests never left the write buffer.
+pub(crate) struct CountWritten<Channel> {
+    io: Channel,
+    buf: BytesMut,
+    progress: Arc<WriteProgress>,
+}
+impl<Channel: AsyncWrite + Unpin> CountWritten<Channel> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.buf.is_empty() {
+            let n = futures::ready!(Pin::new(&mut self.io).poll_write(cx, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.buf.advance(n);
+            self.progress
+                .accepted
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+impl<Channel: AsyncRead + Unpin> AsyncRead for CountWritten<Channel> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+impl<Channel: AsyncWrite + Unpin> AsyncWrite for CountWritten<Channel> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.buf.extend_from_slice(buf);
+        self.progress
+            .encoded
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.progress.hold.load(Ordering::Relaxed) {
+            return Poll::Ready(Ok(()));
+        }
+        futures::ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        futures::ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
 
 struct Receiving<T = ()> {
     handle: RequestHandle<T>,
     resp: Response<BytesMut>,
     length: Option<DecodedLength>,
+    /// Where this request's bytes start. It may have reached the server once the socket
+    /// accepted more than that.
+    offset: u64,
+    complete: bool,
+    head_request: bool,
+    deadline: Option<Instant>,
+    sent_at: Instant,
 }
 
 impl<T> Receiving<T> {
-    pub fn new(handle: RequestHandle<T>) -> Self {
+    pub fn new(
+        handle: RequestHandle<T>,
+        head_request: bool,
+        deadline: Option<Instant>,
+        offset: u64,
+    ) -> Self {
         Self {
             handle,
             resp: Default::default(),
             length: None,
+            offset,
+            complete: false,
+            head_request,
+            deadline,
+            sent_at: Instant::now(),
+        }
+    }
+    /// Why this request fails when its connection ends before the response arrived, given the
+    /// bytes the socket accepted in total.
+    fn unanswered_error(&self, now: Instant, accepted: u64) -> Error {
+        if matches!(self.deadline, Some(d) if d <= now) {
+            Error::Timeout
+        } else if accepted > self.offset {
+            Error::ConnectionReset
+        } else {
+            Error::NeverWritten
+        }
+    }
+}
+/// A request with its response, or why the connection failed.
+type Answered<T> = crate::Result<(RequestHandle<T>, Response<Bytes>)>;
+enum Event<T> {
+    Head,
+    Chunk(Bytes),
+    End(Receiving<T>),
+    Unsolicited(Response<()>),
+}
+/// A piece of a response, as yielded by `HttpClient::poll_response_event`.
+pub enum ResponseEvent<T = ()> {
+    /// The status line and headers of the response to this request.
+    Head(RequestHandle<T>, Response<()>),
+    /// The next piece of the body.
+    Chunk(RequestHandle<T>, Bytes),
+    /// The response is complete; the next event belongs to the following request.
+    End(RequestHandle<T>),
+    /// A response arrived while no request was waiting, such as a `408 Request Timeout` on an
+    /// idle connection. The connection is retired afterwards.
+    Unsolicited(Response<()>),
+}
+#[derive(Clone, Default)]
+pub struct HttpClientConfig {
+    /// Authority used for the `Host` header when the request URI has none.
+    pub default_authority: Option<Authority>,
+    /// Write the request target in origin-form (`/path?query`) instead of the URI as given.
+    pub origin_form: bool,
+}
+impl HttpClientConfig {
+    /// Fills in the `Host` header, unless the request sets one explicitly, and rewrites the
+    /// request target as configured.
+    #[allow(clippy::result_large_err)]
+    pub fn prepare_request<Buf>(
+        &self,
+        mut req: Request<Buf>,
+    ) -> Result<Request<Buf>, Rejected<Buf>> {
+        if !req.headers().contains_key(HOST) {
+            let host = req
+                .uri()
+                .authority()
+                .or(self.default_authority.as_ref())
+                .map(|x| HeaderValue::from_str(x.as_str()));
+            match host {
+                Some(Ok(host)) => {
+                    req.headers_mut().insert(HOST, host);
+                }
+                Some(Err(_)) => {
+                    return Err(Rejected::new(
+                        Error::InvalidRequest("Host name invalid"),
+                        req,
+                    ))
+                }
+                None => {
+                    return Err(Rejected::new(
+                        Error::InvalidRequest("No valid authority"),
+                        req,
+                    ))
+                }
+            }
+        }
+        if self.origin_form && req.uri().scheme().is_some() {
+            let mut parts = http::uri::Parts::default();
+            // Parsed again from `as_str`, which turns the empty path of `http://host` into `/`.
+            parts.path_and_query = req
+                .uri()
+                .path_and_query()
+                .map_or("/", |x| x.as_str())
+                .parse()
+                .ok();
+            match Uri::from_parts(parts) {
+                Ok(uri) => *req.uri_mut() = uri,
+                Err(_) => {
+                    return Err(Rejected::new(
+                        Error::InvalidRequest("Cannot build origin-form target"),
+                        req,
+                    ))
+                }
+            }
+        }
+        Ok(req)
+    }
+}
+/// Fraction of the server's keep-alive timeout an idle connection stops taking requests
+/// early, so that short timeouts stay usable and a replacement connects before it closes.
+const KEEP_ALIVE_MARGIN: f64 = 0.2;
+/// Longest keep-alive timeout taken from a server, so that the expiry cannot overflow.
+const MAX_KEEP_ALIVE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Weight of the newest sample in the latency moving average.
+const LATENCY_EWMA_WEIGHT: f64 = 0.2;
+const NO_REQUEST: Error = Error::Desync("No available request in queue");
+
+fn has_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| x.trim().eq_ignore_ascii_case(token))
+}
+/// Parses `Keep-Alive: timeout=5, max=100` into its timeout and max parameters.
+fn parse_keep_alive(headers: &HeaderMap) -> (Option<Duration>, Option<usize>) {
+    let mut timeout = None;
+    let mut max = None;
+    let params = headers
+        .get_all("keep-alive")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','));
+    for param in params {
+        let mut kv = param.splitn(2, '=').map(str::trim);
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) if k.eq_ignore_ascii_case("timeout") => {
+                timeout = v
+                    .parse()
+                    .ok()
+                    .map(|x| Duration::from_secs(x).min(MAX_KEEP_ALIVE))
+                    .or(timeout)
+            }
+            (Some(k), Some(v)) if k.eq_ignore_ascii_case("max") => max = v.parse().ok().or(max),
+            // Malformed parameters are ignored rather than clearing what was parsed.
+            _ => {}
         }
     }
+    (timeout, max)
 }
 pub struct HttpClient<Channel, Buf = Bytes, T = ()> {
-    pub(crate) conn: Conn<Channel, Buf, ClientTransaction>,
+    pub(crate) conn: Conn<CountWritten<Channel>, Buf, ClientTransaction>,
+    progress: Arc<WriteProgress>,
     queue: std::collections::VecDeque<Receiving<T>>,
     client_id: usize,
+    config: HttpClientConfig,
+    timer: Timer,
+    poisoned: bool,
+    /// No more requests are written, because the server announced it closes the connection
+    /// after the current response or the connection was retired.
+    closing: bool,
+    keep_alive_timeout: Option<Duration>,
+    /// Requests the server still accepts on this connection, from `Keep-Alive: max`.
+    remaining_requests: Option<usize>,
+    idle_since: Instant,
+    created_at: Instant,
+    latency: Option<Duration>,
+    response_count: usize,
 }
 static CLIENT_ID: AtomicUsize = AtomicUsize::new(0);
 impl<Channel: AsyncRead + AsyncWrite + Unpin, Buf: self::Buf, T: Clone>
     HttpClient<Channel, Buf, T>
 {
     pub fn new(io: Channel) -> Self {
+        Self::with_config(io, Default::default())
+    }
+    pub fn with_config(io: Channel, config: HttpClientConfig) -> Self {
+        let progress = Arc::new(WriteProgress::default());
         Self {
-            conn: hyper::proto::Conn::new(io),
+            conn: hyper::proto::Conn::new(CountWritten {
+                io,
+                buf: BytesMut::new(),
+                progress: progress.clone(),
+            }),
+            progress,
             queue: Default::default(),
             client_id: CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            config,
+            timer: Default::default(),
+            poisoned: false,
+            closing: false,
+            keep_alive_timeout: None,
+            remaining_requests: None,
+            idle_since: Instant::now(),
+            created_at: Instant::now(),
+            latency: None,
+            response_count: 0,
         }
     }
     pub fn get_client_id(&self) -> usize {
         self.client_id
     }
+    #[allow(clippy::result_large_err)]
     pub fn request_with_handle(
         &mut self,
-        mut req: Request<Buf>,
+        req: Request<Buf>,
         handle: RequestHandle<T>,
-    ) -> Result<(), Request<Buf>> {
-        if self.conn.can_write_head() {
-            let aur = req
-                .uri()
-                .authority()
-                .expect("No valid authority")
-                .to_string();
-            req.headers_mut().insert(
-                HOST,
-                HeaderValue::from_str(&aur).expect("Host name invalid"),
-            );
-            let (parts, body) = req.into_parts();
-            let head = RequestHead {
-                version: parts.version,
-                subject: RequestLine(parts.method, parts.uri),
-                headers: parts.headers,
-                extensions: parts.extensions,
-            };
-            self.conn.write_full_msg(head, body);
-            self.queue.push_back(Receiving::new(handle));
-            Ok(())
-        } else {
-            Err(req)
+    ) -> Result<(), Rejected<Buf>> {
+        self.request_with_deadline(req, handle, None)
+    }
+    /// Like `request_with_handle`, but fails the request with `Error::Timeout` if no response
+    /// arrived by `deadline`. A timed out request poisons the connection, since the responses
+    /// pipelined behind it can no longer be matched.
+    #[allow(clippy::result_large_err)]
+    pub fn request_with_deadline(
+        &mut self,
+        req: Request<Buf>,
+        handle: RequestHandle<T>,
+        deadline: Option<Instant>,
+    ) -> Result<(), Rejected<Buf>> {
+        if !self.queue.is_empty() {
+            // Move the requests hyper still buffers into `CountWritten` without touching the
+            // socket, so that `encoded` is where this request starts.
+            self.progress.hold.store(true, Ordering::Relaxed);
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            let _ = self.conn.poll_flush(&mut cx);
+            self.progress.hold.store(false, Ordering::Relaxed);
+        }
+        if !self.can_take(req.method()) {
+            return Err(Rejected::new(Error::Busy, req));
+        }
+        let head_request = req.method() == Method::HEAD;
+        let (parts, body) = self.config.prepare_request(req)?.into_parts();
+        let offset = self.progress.encoded.load(Ordering::Relaxed);
+        let head = RequestHead {
+            version: parts.version,
+            subject: RequestLine(parts.method, parts.uri),
+            headers: parts.headers,
+            extensions: parts.extensions,
+        };
+        self.conn.write_full_msg(head, body);
+        if let Some(remaining) = self.remaining_requests.as_mut() {
+            *remaining = remaining.saturating_sub(1);
         }
+        self.queue
+            .push_back(Receiving::new(handle, head_request, deadline, offset));
+        Ok(())
     }
+    #[allow(clippy::result_large_err)]
     pub fn request(
         &mut self,
-        mut req: Request<Buf>,
+        req: Request<Buf>,
         data: T,
-    ) -> Result<RequestHandle<T>, Request<Buf>> {
-        if self.conn.can_write_head() {
-            let aur = req
-                .uri()
-                .authority()
-                .expect("No valid authority")
-                .to_string();
-            req.headers_mut().insert(
-                HOST,
-                HeaderValue::from_str(&aur).expect("Host name invalid"),
-            );
-            let (parts, body) = req.into_parts();
-            let head = RequestHead {
-                version: parts.version,
-                subject: RequestLine(parts.method, parts.uri),
-                headers: parts.headers,
-                extensions: parts.extensions,
-            };
-            self.conn.write_full_msg(head, body);
-            let handle = RequestHandle::unique(data);
-            self.queue.push_back(Receiving::new(handle.clone()));
-            Ok(handle)
-        } else {
-            Err(req)
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        let handle = RequestHandle::unique(data);
+        self.request_with_handle(req, handle.clone())?;
+        Ok(handle)
+    }
+    fn pop_finished(&mut self) -> crate::Result<Receiving<T>> {
+        let finished = self.queue.pop_front().ok_or(NO_REQUEST)?;
+        let now = Instant::now();
+        let sample = now - finished.sent_at;
+        self.latency = Some(match self.latency {
+            Some(latency) => {
+                latency.mul_f64(1.0 - LATENCY_EWMA_WEIGHT) + sample.mul_f64(LATENCY_EWMA_WEIGHT)
+            }
+            None => sample,
+        });
+        self.response_count += 1;
+        if self.queue.is_empty() {
+            self.idle_since = now;
         }
+        Ok(finished)
     }
-    pub fn poll_response(
-        &mut self,
-        cx: &mut Context<'_>,
-    ) -> Poll<Option<std::io::Result<(RequestHandle<T>, Response<Bytes>)>>> {
+    fn observe_keep_alive(&mut self, version: http::Version, headers: &HeaderMap) {
+        if has_token(headers, "close")
+            || version == http::Version::HTTP_10 && !has_token(headers, "keep-alive")
+        {
+            self.closing = true;
+        }
+        let (timeout, max) = parse_keep_alive(headers);
+        if timeout.is_some() {
+            self.keep_alive_timeout = timeout;
+        }
+        if let Some(max) = max {
+            // Requests pipelined behind the current response were written after the server
+            // counted this one.
+            let pipelined = self.queue.len().saturating_sub(1);
+            self.remaining_requests = Some(max.saturating_sub(pipelined));
+        }
+    }
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<crate::Result<Event<T>>>> {
+        if matches!(self.queue.front(), Some(x) if x.complete) {
+            return Poll::Ready(Some(self.pop_finished().map(Event::End)));
+        }
+        if self.poisoned {
+            return Poll::Ready(None);
+        }
+        if let Some(deadline) = self.queue.iter().filter_map(|x| x.deadline).min() {
+            if self.timer.poll_at(cx, deadline).is_ready() {
+                self.poisoned = true;
+                return Poll::Ready(Some(Err(Error::Timeout)));
+            }
+        }
         let _ = self.conn.poll_flush(cx)?;
         if self.conn.can_read_head() {
             match futures::ready!(self.conn.poll_read_head(cx)) {
                 Some(Ok((head, length, _))) => {
-                    let response = ensure!(self.queue.front_mut(), "No available request in queue");
+                    self.observe_keep_alive(head.version, &head.headers);
+                    let response = match self.queue.front_mut() {
+                        Some(response) => response,
+                        None => {
+                            self.poisoned = true;
+                            let mut unsolicited = Response::new(());
+                            *unsolicited.version_mut() = head.version;
+                            *unsolicited.status_mut() = head.subject;
+                            *unsolicited.headers_mut() = head.headers;
+                            *unsolicited.extensions_mut() = head.extensions;
+                            return Poll::Ready(Some(Ok(Event::Unsolicited(unsolicited))));
+                        }
+                    };
                     *response.resp.version_mut() = head.version;
                     *response.resp.status_mut() = head.subject;
                     *response.resp.headers_mut() = head.headers;
                     *response.resp.extensions_mut() = head.extensions;
                     response.length = Some(length);
+                    // HEAD, 204 and 304 responses carry no body, so they are complete as soon as
+                    // the head is parsed. Interim 1xx responses are skipped by the parser and
+                    // never reach this point, except 101 which hands the connection over to
+                    // another protocol.
+                    response.complete = length == DecodedLength::ZERO;
+                    if response.resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+                        self.poisoned = true;
+                    }
+                    return Poll::Ready(Some(Ok(Event::Head)));
                 }
-                Some(Err(err)) => {
-                    return Poll::Ready(Some(Err(std::io::Error::new(ErrorKind::Other, err))))
-                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(Error::Parse(err)))),
                 None => return Poll::Ready(None),
             }
         }
         if self.conn.can_read_body() {
             match futures::ready!(self.conn.poll_read_body(cx)) {
                 Some(Ok(chunk)) => {
-                    let response = ensure!(self.queue.front_mut(), "No available request in queue");
-                    let length = ensure!(response.length.as_mut(), "Does not receive a length");
+                    let response = self.queue.front_mut().ok_or(NO_REQUEST)?;
+                    let length = response
+                        .length
+                        .as_mut()
+                        .ok_or(Error::Desync("Does not receive a length"))?;
                     length.sub_if(chunk.len() as _);
+                    response.complete = length.into_opt() == Some(0);
+                    return Poll::Ready(Some(Ok(Event::Chunk(chunk))));
+                }
+                None => return Poll::Ready(Some(self.pop_finished().map(Event::End))),
+                Some(Err(err)) => return Poll::Ready(Some(Err(Error::Io(err)))),
+            }
+        }
+        // The server closed the connection between responses.
+        if self.conn.is_read_closed() {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+    pub fn poll_response(&mut self, cx: &mut Context<'_>) -> Poll<Option<Answered<T>>> {
+        loop {
+            match futures::ready!(self.poll_event(cx)?) {
+                Some(Event::Head) => {}
+                Some(Event::Chunk(chunk)) => {
+                    let response = self.queue.front_mut().ok_or(NO_REQUEST)?;
                     response.resp.body_mut().extend_from_slice(chunk.as_ref());
-                    if length.into_opt() == Some(0) {
-                        return Poll::Ready(Some(Ok(ensure!(
-                            self.queue
-                                .pop_front()
-                                .map(|x| (x.handle, x.resp.map(|body| body.freeze()))),
-                            "No available request in queue"
-                        ))));
-                    }
                 }
-                None => {
-                    return Poll::Ready(Some(Ok(ensure!(
-                        self.queue
-                            .pop_front()
-                            .map(|x| (x.handle, x.resp.map(|body| body.freeze()))),
-                        "No available request in queue"
-                    ))))
+                Some(Event::End(x)) => {
+                    return Poll::Ready(Some(Ok((x.handle, x.resp.map(|body| body.freeze())))))
                 }
-                Some(Err(err)) => {
-                    return Poll::Ready(Some(Err(std::io::Error::new(ErrorKind::Other, err))))
+                Some(Event::Unsolicited(response)) => {
+                    return Poll::Ready(Some(Err(Error::Unsolicited(response.status()))))
                 }
+                None => return Poll::Ready(None),
             }
         }
-        Poll::Pending
+    }
+    /// Streaming counterpart of `poll_response`: yields each response head as soon as it is
+    /// parsed, then its body chunks as they arrive, then `ResponseEvent::End`. Responses come
+    /// out in the order their requests were written.
+    pub fn poll_response_event(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<crate::Result<ResponseEvent<T>>>> {
+        let event = match futures::ready!(self.poll_event(cx)?) {
+            Some(Event::Head) => {
+                let response = self.queue.front_mut().ok_or(NO_REQUEST)?;
+                let mut head = Response::new(());
+                *head.version_mut() = response.resp.version();
+                *head.status_mut() = response.resp.status();
+                *head.headers_mut() = std::mem::take(response.resp.headers_mut());
+                *head.extensions_mut() = std::mem::take(response.resp.extensions_mut());
+                ResponseEvent::Head(response.handle.clone(), head)
+            }
+            Some(Event::Chunk(chunk)) => {
+                let response = self.queue.front_mut().ok_or(NO_REQUEST)?;
+                ResponseEvent::Chunk(response.handle.clone(), chunk)
+            }
+            Some(Event::End(x)) => ResponseEvent::End(x.handle),
+            Some(Event::Unsolicited(response)) => ResponseEvent::Unsolicited(response),
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(Ok(event)))
     }
     pub fn queue_len(&self) -> usize {
         self.queue.len()
     }
+    /// Whether a request with `method` can be written now. The parser decides whether a
+    /// response has a body from the method of the last request written, so HEAD requests never
+    /// share the pipeline with other methods.
+    pub fn can_take(&self, method: &Method) -> bool {
+        let head_request = method == Method::HEAD;
+        self.conn.can_write_head()
+            && self.accepts_requests()
+            && !matches!(self.queue.back(), Some(x) if x.head_request != head_request)
+    }
+    /// Whether new requests may still be written to this connection. This turns false once the
+    /// server announced `Connection: close`, its `Keep-Alive` request budget is used up, or the
+    /// connection has been idle for almost its keep-alive timeout.
+    pub fn accepts_requests(&self) -> bool {
+        if self.closing || self.poisoned || self.remaining_requests == Some(0) {
+            return false;
+        }
+        match self.keep_alive_expiry() {
+            Some(expiry) => Instant::now() < expiry,
+            None => true,
+        }
+    }
+    /// When this idle connection stops taking requests because the server's keep-alive timeout
+    /// is about to close it. `None` while requests are in flight or no timeout was announced.
+    pub fn keep_alive_expiry(&self) -> Option<Instant> {
+        match self.keep_alive_timeout {
+            Some(timeout) if self.queue.is_empty() => {
+                Some(self.idle_since + timeout.mul_f64(1.0 - KEEP_ALIVE_MARGIN))
+            }
+            _ => None,
+        }
+    }
+    /// How long ago the connection was established.
+    pub fn age(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+    /// Moving average of the time from writing a request until its response completed.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+    /// Number of responses completed on this connection.
+    pub fn response_count(&self) -> usize {
+        self.response_count
+    }
+    /// Stops writing new requests. The requests already written still get their responses.
+    pub fn retire(&mut self) {
+        self.closing = true;
+    }
+    /// Whether later responses on this connection can no longer be trusted, because a request
+    /// timed out or the server switched protocols.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+    /// Removes every request still waiting for a response, with the reason it failed.
+    /// Call this once `poll_response` has reported an error or end of stream.
+    pub fn take_unanswered(&mut self) -> impl Iterator<Item = (RequestHandle<T>, Error)> + '_ {
+        let now = Instant::now();
+        let accepted = self.progress.accepted.load(Ordering::Relaxed);
+        self.queue.drain(..).map(move |x| {
+            let err = x.unanswered_error(now, accepted);
+            (x.handle, err)
+        })
+    }
+}
+
+/// Yields every request's outcome in order. Once the connection ends, the requests still
+/// waiting are yielded with their errors, then the stream ends.
+impl<Channel, Buf, T> Stream for HttpClient<Channel, Buf, T>
+where
+    Channel: AsyncRead + AsyncWrite + Unpin,
+    Buf: self::Buf + Unpin,
+    T: Clone + Unpin,
+{
+    type Item = (RequestHandle<T>, crate::Result<Response<Bytes>>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match futures::ready!(this.poll_response(cx)) {
+            Some(Ok((handle, response))) => return Poll::Ready(Some((handle, Ok(response)))),
+            Some(Err(err)) => {
+                debug!("Connection failed: {}", err);
+                this.poisoned = true;
+            }
+            None => this.poisoned = true,
+        }
+        let now = Instant::now();
+        let accepted = this.progress.accepted.load(Ordering::Relaxed);
+        Poll::Ready(this.queue.pop_front().map(|x| {
+            let err = x.unanswered_error(now, accepted);
+            (x.handle, Err(err))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    type Polled = Option<crate::Result<(RequestHandle, Response<Bytes>)>>;
+
+    fn get(path: &str) -> Request<Bytes> {
+        Request::get(format!("http://example.com{}", path))
+            .body(Bytes::new())
+            .unwrap()
+    }
+    /// Polls the client once, returning `None` if it is pending.
+    fn poll_once(client: &mut HttpClient<DuplexStream>) -> Option<Polled> {
+        futures::future::poll_fn(|cx| client.poll_response(cx)).now_or_never()
+    }
+    async fn next_response(client: &mut HttpClient<DuplexStream>) -> (usize, Response<Bytes>) {
+        let (handle, response) = futures::future::poll_fn(|cx| client.poll_response(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        (handle.id(), response)
+    }
+    /// Pipelines `first` and a GET, answers them with `response` followed by a regular
+    /// response, and returns the response to `first` once the GET's has been read as well.
+    async fn pipelined(first: Request<Bytes>, response: &[u8]) -> Response<Bytes> {
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        let a = client.request(first, ()).unwrap();
+        let b = client.request(get("/next"), ()).unwrap();
+        server.write_all(response).await.unwrap();
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nnext")
+            .await
+            .unwrap();
+        let (id, first) = next_response(&mut client).await;
+        assert_eq!(id, a.id());
+        let (id, next) = next_response(&mut client).await;
+        assert_eq!(id, b.id());
+        assert_eq!(next.status(), StatusCode::OK);
+        assert_eq!(next.body().as_ref(), b"next");
+        first
+    }
+    fn unanswered(client: &mut HttpClient<DuplexStream>) -> Vec<(usize, Error)> {
+        client
+            .take_unanswered()
+            .map(|(handle, err)| (handle.id(), err))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn flushed_request_is_reset_and_later_one_never_written() {
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        let first = client.request(get("/first"), ()).unwrap();
+        assert!(poll_once(&mut client).is_none());
+        let mut buf = vec![0; 1024];
+        assert!(server.read(&mut buf).await.unwrap() > 0);
+        drop(server);
+
+        let second = client.request(get("/second"), ()).unwrap();
+        assert!(matches!(poll_once(&mut client), Some(Some(Err(_)))));
+        let failed = unanswered(&mut client);
+        assert!(matches!(failed[0], (id, Error::ConnectionReset) if id == first.id()));
+        assert!(matches!(failed[1], (id, Error::NeverWritten) if id == second.id()));
+    }
+
+    #[tokio::test]
+    async fn partly_written_request_is_reset() {
+        let (io, server) = tokio::io::duplex(16);
+        let mut client = HttpClient::new(io);
+        let handle = client.request(get("/partial"), ()).unwrap();
+        assert!(poll_once(&mut client).is_none());
+        drop(server);
+
+        assert!(matches!(poll_once(&mut client), Some(Some(Err(_)))));
+        let failed = unanswered(&mut client);
+        assert!(matches!(failed[..], [(id, Error::ConnectionReset)] if id == handle.id()));
+    }
+
+    #[tokio::test]
+    async fn pipelined_request_behind_partly_written_one_is_never_written() {
+        let (io, server) = tokio::io::duplex(16);
+        let mut client = HttpClient::new(io);
+        let first = client.request(get("/first"), ()).unwrap();
+        let second = client.request(get("/second"), ()).unwrap();
+        assert!(poll_once(&mut client).is_none());
+        drop(server);
+
+        assert!(matches!(poll_once(&mut client), Some(Some(Err(_)))));
+        let failed = unanswered(&mut client);
+        assert!(matches!(failed[0], (id, Error::ConnectionReset) if id == first.id()));
+        assert!(matches!(failed[1], (id, Error::NeverWritten) if id == second.id()));
+    }
+
+    #[tokio::test]
+    async fn unwritten_request_of_dead_connection_is_never_written() {
+        let (io, server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        drop(server);
+        let handle = client.request(get("/"), ()).unwrap();
+
+        assert!(poll_once(&mut client).is_some());
+        let failed = unanswered(&mut client);
+        assert!(matches!(failed[..], [(id, Error::NeverWritten)] if id == handle.id()));
+    }
+
+    #[tokio::test]
+    async fn timed_out_request_fails_the_pipeline_behind_it() {
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let a = RequestHandle::unique(());
+        client
+            .request_with_deadline(get("/a"), a, Some(deadline))
+            .unwrap();
+        let b = client.request(get("/b"), ()).unwrap();
+        assert!(poll_once(&mut client).is_none());
+        let mut buf = vec![0; 1024];
+        assert!(server.read(&mut buf).await.unwrap() > 0);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let c = client.request(get("/c"), ()).unwrap();
+        // Late responses must not be handed to the requests behind the timed out one.
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na")
+            .await
+            .unwrap();
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            poll_once(&mut client),
+            Some(Some(Err(Error::Timeout)))
+        ));
+        assert!(client.is_poisoned());
+        assert!(matches!(poll_once(&mut client), Some(None)));
+        let failed = unanswered(&mut client);
+        assert!(matches!(failed[0], (id, Error::Timeout) if id == a.id()));
+        assert!(matches!(failed[1], (id, Error::ConnectionReset) if id == b.id()));
+        assert!(matches!(failed[2], (id, Error::NeverWritten) if id == c.id()));
+    }
+
+    #[tokio::test]
+    async fn head_responses_have_no_body() {
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        let head = |path: &str| {
+            Request::head(format!("http://example.com{}", path))
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let a = client.request(head("/a"), ()).unwrap();
+        let b = client.request(head("/b"), ()).unwrap();
+        // A GET behind a HEAD would have its response parsed as bodiless.
+        assert!(!client.can_take(&Method::GET));
+        assert!(matches!(
+            client.request(get("/c"), ()),
+            Err(Rejected {
+                error: Error::Busy,
+                ..
+            })
+        ));
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n")
+            .await
+            .unwrap();
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\n")
+            .await
+            .unwrap();
+        for (expected, length) in [(a.id(), "5"), (b.id(), "7")] {
+            let (id, response) = next_response(&mut client).await;
+            assert_eq!(id, expected);
+            assert_eq!(response.headers()["content-length"], length);
+            assert!(response.body().is_empty());
+        }
+
+        assert!(client.can_take(&Method::GET));
+        let c = client.request(get("/c"), ()).unwrap();
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+        let (id, response) = next_response(&mut client).await;
+        assert_eq!(id, c.id());
+        assert_eq!(response.body().as_ref(), b"hello");
+    }
+
+    /// Reads the next streamed event, as `(kind, handle id, chunk)`.
+    async fn next_event(client: &mut HttpClient<DuplexStream>) -> (&'static str, usize, Bytes) {
+        let event = futures::future::poll_fn(|cx| client.poll_response_event(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        match event {
+            ResponseEvent::Head(handle, _) => ("head", handle.id(), Bytes::new()),
+            ResponseEvent::Chunk(handle, chunk) => ("chunk", handle.id(), chunk),
+            ResponseEvent::End(handle) => ("end", handle.id(), Bytes::new()),
+            ResponseEvent::Unsolicited(_) => ("unsolicited", 0, Bytes::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_response_is_streamed() {
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        let handle = client.request(get("/"), ()).unwrap();
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut client).await,
+            ("head", handle.id(), Bytes::new())
+        );
+        assert_eq!(
+            next_event(&mut client).await,
+            ("chunk", handle.id(), Bytes::from("hello"))
+        );
+        server.write_all(b"6\r\n world\r\n0\r\n\r\n").await.unwrap();
+        assert_eq!(
+            next_event(&mut client).await,
+            ("chunk", handle.id(), Bytes::from(" world"))
+        );
+        assert_eq!(
+            next_event(&mut client).await,
+            ("end", handle.id(), Bytes::new())
+        );
+        assert_eq!(client.queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn bodiless_response_streams_head_then_end() {
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        let handle = client.request(get("/"), ()).unwrap();
+        server
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut client).await,
+            ("head", handle.id(), Bytes::new())
+        );
+        assert_eq!(
+            next_event(&mut client).await,
+            ("end", handle.id(), Bytes::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_stream_in_request_order() {
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = HttpClient::new(io);
+        let a = client.request(get("/a"), ()).unwrap().id();
+        let b = client.request(get("/b"), ()).unwrap().id();
+        server
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+                  HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb",
+            )
+            .await
+            .unwrap();
+        let mut events = vec![];
+        for _ in 0..6 {
+            events.push(next_event(&mut client).await);
+        }
+        assert_eq!(
+            events,
+            [
+                ("head", a, Bytes::new()),
+                ("chunk", a, Bytes::from("a")),
+                ("end", a, Bytes::new()),
+                ("head", b, Bytes::new()),
+                ("chunk", b, Bytes::from("b")),
+                ("end", b, Bytes::new()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn response_on_idle_connection_is_unsolicited() {
+        let timeout: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\n\r\n";
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client: HttpClient<DuplexStream> = HttpClient::new(io);
+        server.write_all(timeout).await.unwrap();
+        let event = futures::future::poll_fn(|cx| client.poll_response_event(cx)).await;
+        match event {
+            Some(Ok(ResponseEvent::Unsolicited(response))) => {
+                assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT)
+            }
+            _ => panic!("expected an unsolicited response"),
+        }
+        assert!(client.is_poisoned());
+        assert!(!client.accepts_requests());
+
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client: HttpClient<DuplexStream> = HttpClient::new(io);
+        server.write_all(timeout).await.unwrap();
+        let polled = futures::future::poll_fn(|cx| client.poll_response(cx)).await;
+        assert!(matches!(
+            polled,
+            Some(Err(Error::Unsolicited(StatusCode::REQUEST_TIMEOUT)))
+        ));
+        assert!(client.is_poisoned());
+    }
+
+    #[tokio::test]
+    async fn stream_yields_unanswered_requests_then_ends() {
+        use futures::StreamExt;
+        let (io, mut server) = tokio::io::duplex(1 << 16);
+        let mut client: HttpClient<DuplexStream> = HttpClient::new(io);
+        let a = client.request(get("/a"), ()).unwrap().id();
+        let b = client.request(get("/b"), ()).unwrap().id();
+        let c = client.request(get("/c"), ()).unwrap().id();
+        assert!(poll_once(&mut client).is_none());
+        let mut buf = vec![0; 1024];
+        assert!(server.read(&mut buf).await.unwrap() > 0);
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na")
+            .await
+            .unwrap();
+        drop(server);
+
+        let (handle, result) = client.next().await.unwrap();
+        assert_eq!(handle.id(), a);
+        assert_eq!(result.unwrap().body().as_ref(), b"a");
+        for expected in [b, c] {
+            let (handle, result) = client.next().await.unwrap();
+            assert_eq!(handle.id(), expected);
+            assert!(matches!(result, Err(Error::ConnectionReset)));
+        }
+        assert!(client.next().await.is_none());
+        assert_eq!(client.queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn no_content_response_has_no_body() {
+        let response = pipelined(get("/"), b"HTTP/1.1 204 No Content\r\n\r\n").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn not_modified_response_has_no_body() {
+        // The length describes the cached representation, not this response.
+        let response = pipelined(
+            get("/"),
+            b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn interim_responses_are_skipped() {
+        let response = pipelined(
+            get("/"),
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+              HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfinal",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"final");
+    }
+
+    #[test]
+    fn prepare_request_fills_host_and_target() {
+        let plain = HttpClientConfig::default();
+        let origin_form = HttpClientConfig {
+            origin_form: true,
+            ..Default::default()
+        };
+        let with_default = HttpClientConfig {
+            default_authority: Some("default.com:8080".parse().unwrap()),
+            ..Default::default()
+        };
+        let both = HttpClientConfig {
+            origin_form: true,
+            ..with_default.clone()
+        };
+        let cases = vec![
+            (
+                &plain,
+                "http://example.com/a?b",
+                None,
+                Some(("http://example.com/a?b", "example.com")),
+            ),
+            (
+                &plain,
+                "http://example.com/",
+                Some("other.com"),
+                Some(("http://example.com/", "other.com")),
+            ),
+            (&plain, "/a", Some("other.com"), Some(("/a", "other.com"))),
+            (&plain, "/a", None, None),
+            (
+                &origin_form,
+                "http://example.com:81/a?b",
+                None,
+                Some(("/a?b", "example.com:81")),
+            ),
+            (
+                &origin_form,
+                "http://example.com",
+                None,
+                Some(("/", "example.com")),
+            ),
+            (&origin_form, "/a", None, None),
+            (&with_default, "/a", None, Some(("/a", "default.com:8080"))),
+            (
+                &with_default,
+                "http://example.com/a",
+                None,
+                Some(("http://example.com/a", "example.com")),
+            ),
+            (
+                &both,
+                "http://example.com/a",
+                None,
+                Some(("/a", "example.com")),
+            ),
+            (&both, "/a", None, Some(("/a", "default.com:8080"))),
+        ];
+        for (config, uri, host, expected) in cases {
+            let mut request = Request::get(uri).header("x-tag", "kept");
+            if let Some(host) = host {
+                request = request.header(HOST, host);
+            }
+            let request = request.body(Bytes::new()).unwrap();
+            match (config.prepare_request(request), expected) {
+                (Ok(request), Some((target, host))) => {
+                    assert_eq!(request.uri(), target, "{}", uri);
+                    assert_eq!(request.headers()[HOST], host, "{}", uri);
+                }
+                // A rejected request is handed back as it was submitted.
+                (Err(rejected), None) => {
+                    assert!(matches!(rejected.error, Error::InvalidRequest(_)));
+                    assert_eq!(rejected.request.uri(), uri);
+                    assert!(!rejected.request.headers().contains_key(HOST));
+                    assert_eq!(rejected.request.headers()["x-tag"], "kept");
+                }
+                (Ok(request), None) => panic!("{} accepted as {}", uri, request.uri()),
+                (Err(rejected), Some(_)) => panic!("{} rejected: {}", uri, rejected.error),
+            }
+        }
+    }
+
+    #[test]
+    fn keep_alive_parameters() {
+        let secs = |x| Some(Duration::from_secs(x));
+        let cases: Vec<(&[&[u8]], _)> = vec![
+            (&[b"timeout=5, max=100"], (secs(5), Some(100))),
+            (&[b"timeout=5"], (secs(5), None)),
+            (&[b"max=3"], (None, Some(3))),
+            (&[b"TIMEOUT = 7 , Max=2"], (secs(7), Some(2))),
+            (&[b"timeout=5", b"max=9"], (secs(5), Some(9))),
+            (&[b"foo=1, timeout=2, bar"], (secs(2), None)),
+            (&[b""], (None, None)),
+            (&[b"timeout"], (None, None)),
+            (&[b"timeout=, max="], (None, None)),
+            (&[b"timeout=abc, max=-1"], (None, None)),
+            (&[b"timeout=5.5"], (None, None)),
+            (&[b"timeout=\"5\""], (None, None)),
+            (&[b"timeout=5, timeout=x"], (secs(5), None)),
+            (&[b"timeout=\xff5", b"timeout=3"], (secs(3), None)),
+            (&[b"timeout=86401"], (secs(86400), None)),
+            (&[b"timeout=18446744073709551615"], (secs(86400), None)),
+        ];
+        for (values, expected) in cases {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append("keep-alive", HeaderValue::from_bytes(value).unwrap());
+            }
+            assert_eq!(parse_keep_alive(&headers), expected, "{:?}", values);
+        }
+
+        let (io, _server) = tokio::io::duplex(16);
+        let mut client: HttpClient<DuplexStream> = HttpClient::new(io);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "keep-alive",
+            HeaderValue::from_static("timeout=18446744073709551615"),
+        );
+        client.observe_keep_alive(http::Version::HTTP_11, &headers);
+        assert!(client.keep_alive_expiry().is_some());
+        assert!(client.accepts_requests());
+    }
 }
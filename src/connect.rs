@@ -0,0 +1,46 @@
+use crate::Origin;
+use futures::future::BoxFuture;
+use futures::Future;
+
+/// What a connector knows about the connection it is asked for.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectContext<'a> {
+    /// The origin the pool serves, if it was configured with one.
+    pub origin: Option<&'a Origin>,
+    /// Lowest index not taken by another connection of the pool or attempt in progress. It is
+    /// stable while the pool keeps its size, so a connector can map it to a source address or
+    /// one of the resolved IPs.
+    pub slot: usize,
+    /// Attempts since the pool last connected successfully, this one included.
+    pub attempt: u32,
+    /// Why the previous attempt failed, if it did.
+    pub previous_error: Option<&'a std::io::Error>,
+}
+
+/// Opens the connections of a pool.
+pub trait Connect<Channel>: Send {
+    fn connect(&self, cx: &ConnectContext<'_>) -> BoxFuture<'static, std::io::Result<Channel>>;
+}
+
+impl<Channel, Func, Fut> Connect<Channel> for Func
+where
+    Func: Fn(&ConnectContext<'_>) -> Fut + Send,
+    Fut: Future<Output = std::io::Result<Channel>> + Send + 'static,
+{
+    fn connect(&self, cx: &ConnectContext<'_>) -> BoxFuture<'static, std::io::Result<Channel>> {
+        Box::pin(self(cx))
+    }
+}
+
+/// Adapts a builder closure that ignores the context, as taken by `HttpClientPool::new`.
+pub struct FnConnector<Func>(pub Func);
+
+impl<Channel, Func, Fut> Connect<Channel> for FnConnector<Func>
+where
+    Func: Fn() -> Fut + Send,
+    Fut: Future<Output = std::io::Result<Channel>> + Send + 'static,
+{
+    fn connect(&self, _: &ConnectContext<'_>) -> BoxFuture<'static, std::io::Result<Channel>> {
+        Box::pin((self.0)())
+    }
+}
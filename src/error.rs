@@ -0,0 +1,108 @@
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Why a request or connection failed.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the connection failed.
+    Io(std::io::Error),
+    /// The server sent something that is not a valid HTTP/1 response.
+    Parse(hyper::Error),
+    /// A response arrived while no request was waiting for one. The connection is retired.
+    Unsolicited(http::StatusCode),
+    /// The responses on a connection no longer line up with the requests written to it.
+    Desync(&'static str),
+    /// Establishing a connection failed while no other connection could take the request.
+    Connect(std::io::Error),
+    /// The request was at least partly written, but the connection closed before its response
+    /// arrived. The server may or may not have processed it.
+    ConnectionReset,
+    /// The connection broke after a request that is not safe to replay, or has used up its
+    /// replays, was written. The server may or may not have processed it.
+    OutcomeUnknown,
+    /// The connection closed before any byte of the request was handed to the socket, so the
+    /// server never saw it.
+    NeverWritten,
+    /// The request's deadline passed before its response arrived.
+    Timeout,
+    /// The request outlived its time-to-live while queued and was never sent.
+    Expired,
+    /// The request was abandoned before it completed.
+    Cancelled,
+    /// The request cannot be written as given.
+    InvalidRequest(&'static str),
+    /// The connection cannot take another request right now.
+    Busy,
+    /// Connecting to the server keeps failing, so the pool's circuit breaker is open.
+    CircuitOpen,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::Parse(err) => write!(f, "parse error: {}", err),
+            Error::Unsolicited(status) => write!(f, "unsolicited response: {}", status),
+            Error::Desync(reason) => write!(f, "pipeline desync: {}", reason),
+            Error::Connect(err) => write!(f, "connect error: {}", err),
+            Error::ConnectionReset => write!(f, "connection reset before response"),
+            Error::OutcomeUnknown => write!(f, "connection broke, request outcome unknown"),
+            Error::NeverWritten => write!(f, "connection closed before request was written"),
+            Error::Timeout => write!(f, "request timed out"),
+            Error::Expired => write!(f, "request expired, never sent"),
+            Error::Cancelled => write!(f, "request cancelled"),
+            Error::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+            Error::Busy => write!(f, "connection busy"),
+            Error::CircuitOpen => write!(f, "circuit open, server unreachable"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) | Error::Connect(err) => Some(err),
+            Error::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// A request that was not accepted, handed back together with the reason. It is as large as
+/// the request, which is why the functions returning it allow `clippy::result_large_err`.
+#[derive(Debug)]
+pub struct Rejected<Buf> {
+    pub error: Error,
+    pub request: http::Request<Buf>,
+}
+
+impl<Buf> Rejected<Buf> {
+    pub fn new(error: Error, request: http::Request<Buf>) -> Self {
+        Self { error, request }
+    }
+}
+
+impl<Buf> fmt::Display for Rejected<Buf> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request rejected: {}", self.error)
+    }
+}
+
+impl<Buf: fmt::Debug> std::error::Error for Rejected<Buf> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<Buf> From<Rejected<Buf>> for Error {
+    fn from(rejected: Rejected<Buf>) -> Self {
+        rejected.error
+    }
+}
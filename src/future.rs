@@ -0,0 +1,32 @@
+use crate::Error;
+use bytes::Bytes;
+use futures::channel::oneshot;
+use futures::{Future, FutureExt};
+use http::Response;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub(crate) type ResponseSender = oneshot::Sender<crate::Result<Response<Bytes>>>;
+
+/// Resolves with the response to one request submitted through `HttpClientPool::send`.
+/// Resolves with `Error::Cancelled` if the pool is dropped before the response arrives.
+pub struct ResponseFuture {
+    rx: oneshot::Receiver<crate::Result<Response<Bytes>>>,
+}
+
+impl ResponseFuture {
+    pub(crate) fn channel() -> (ResponseSender, Self) {
+        let (tx, rx) = oneshot::channel();
+        (tx, Self { rx })
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = crate::Result<Response<Bytes>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.rx
+            .poll_unpin(cx)
+            .map(|x| x.unwrap_or(Err(Error::Cancelled)))
+    }
+}
@@ -0,0 +1,178 @@
+use crate::future::ResponseSender;
+use crate::{HttpClientPool, RequestOptions, ResponseFuture};
+use futures::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tracing::*;
+
+type Submission<Buf> = (http::Request<Buf>, RequestOptions, ResponseSender);
+
+/// A cheap, cloneable handle to a pool driven elsewhere. Created by `HttpClientPool::split`
+/// or `HttpClientPool::spawn`; the driver stops once every handle is dropped and all submitted
+/// requests have finished.
+pub struct PoolHandle<Buf = bytes::Bytes> {
+    tx: mpsc::UnboundedSender<Submission<Buf>>,
+}
+
+impl<Buf> Clone for PoolHandle<Buf> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<Buf> PoolHandle<Buf> {
+    pub fn send(&self, request: http::Request<Buf>) -> ResponseFuture {
+        self.send_with_options(request, Default::default())
+    }
+    pub fn send_with_options(
+        &self,
+        request: http::Request<Buf>,
+        options: RequestOptions,
+    ) -> ResponseFuture {
+        let (tx, future) = ResponseFuture::channel();
+        // If the driver is gone the sender is dropped with the message, which resolves the
+        // future with `Error::Cancelled`.
+        let _ = self.tx.send((request, options, tx));
+        future
+    }
+}
+
+/// Drives a pool, taking requests from its `PoolHandle`s. Created by `HttpClientPool::split`;
+/// it completes once every handle is dropped and all submitted requests have finished.
+pub struct PoolDriver<Channel, Buf = bytes::Bytes, T = ()> {
+    pool: HttpClientPool<Channel, Buf, T>,
+    rx: mpsc::UnboundedReceiver<Submission<Buf>>,
+    closed: bool,
+}
+
+impl<Channel, Buf, T> Future for PoolDriver<Channel, Buf, T>
+where
+    Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Buf: bytes::Buf + Clone + Unpin,
+    T: Clone + Default + Unpin,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        while !this.closed {
+            match this.rx.poll_recv(cx) {
+                Poll::Ready(Some((request, options, tx))) => this.pool.submit(request, options, tx),
+                Poll::Ready(None) => this.closed = true,
+                Poll::Pending => break,
+            }
+        }
+        while let Poll::Ready((handle, _)) = this.pool.poll_response(cx) {
+            warn!(
+                "Dropping response to request {} nobody waits for",
+                handle.id()
+            );
+        }
+        if this.closed && this.pool.waiting_count() == 0 {
+            debug!("All pool handles dropped, driver stopped");
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<Channel, Buf, T> HttpClientPool<Channel, Buf, T>
+where
+    Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Buf: bytes::Buf + Clone + Unpin,
+    T: Clone + Default + Unpin,
+{
+    /// Splits the pool into a handle for submitting requests and the driver that runs them.
+    /// The driver has to be awaited or spawned for the handle's futures to make progress.
+    pub fn split(self) -> (PoolHandle<Buf>, PoolDriver<Channel, Buf, T>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let driver = PoolDriver {
+            pool: self,
+            rx,
+            closed: false,
+        };
+        (PoolHandle { tx }, driver)
+    }
+}
+
+impl<Channel, Buf, T> HttpClientPool<Channel, Buf, T>
+where
+    Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Buf: bytes::Buf + Clone + Send + Unpin + 'static,
+    T: Clone + Default + Send + Unpin + 'static,
+{
+    /// Moves the pool onto a tokio task that drives it, and returns a handle for submitting
+    /// requests from any task or thread.
+    pub fn spawn(self) -> PoolHandle<Buf> {
+        let (handle, driver) = self.split();
+        tokio::spawn(driver);
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn get(path: &str) -> http::Request<Bytes> {
+        http::Request::get(format!("http://example.com{}", path))
+            .body(Bytes::new())
+            .unwrap()
+    }
+    /// Answers every request on the connection with its own path as the body.
+    async fn echo_path(mut server: tokio::io::DuplexStream) {
+        loop {
+            let mut head = vec![];
+            while !head.ends_with(b"\r\n\r\n") {
+                match server.read_u8().await {
+                    Ok(byte) => head.push(byte),
+                    Err(_) => return,
+                }
+            }
+            let head = String::from_utf8(head).unwrap();
+            let uri = head.split(' ').nth(1).unwrap();
+            let path = uri.trim_start_matches("http://example.com");
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                path.len(),
+                path
+            );
+            server.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_submitted_while_driver_runs() {
+        let pool: HttpClientPool<_> = HttpClientPool::new(
+            || async {
+                let (client, server) = tokio::io::duplex(1 << 16);
+                tokio::spawn(echo_path(server));
+                Ok(client)
+            },
+            Default::default(),
+        );
+        let (handle, driver) = pool.split();
+        let driver = tokio::spawn(driver);
+        let first = handle.send(get("/first")).await.unwrap();
+        assert_eq!(first.body().as_ref(), b"/first");
+        let senders: Vec<_> = (0..4)
+            .map(|i| {
+                let handle = handle.clone();
+                tokio::spawn(async move { handle.send(get(&format!("/{}", i))).await })
+            })
+            .collect();
+        for (i, sender) in senders.into_iter().enumerate() {
+            let response = sender.await.unwrap().unwrap();
+            assert_eq!(response.body().as_ref(), format!("/{}", i).as_bytes());
+        }
+        drop(handle);
+        driver.await.unwrap();
+    }
+}
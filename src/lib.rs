@@ -1,13 +1,33 @@
+mod balance;
 mod client;
+mod connect;
+mod error;
+mod future;
+mod handle;
+mod limit;
+mod origin;
 mod pool;
 pub mod stat;
+mod tcp;
+mod throttle;
+mod timer;
 
+pub use balance::*;
 pub use client::*;
+pub use connect::*;
+pub use error::*;
+pub use future::*;
+pub use handle::*;
+pub use limit::*;
+pub use origin::*;
 pub use pool::*;
+pub use tcp::*;
+pub use throttle::ThrottlePolicy;
 
 use std::sync::atomic::Ordering;
 
 #[macro_export]
+#[deprecated(note = "wraps errors in `io::Error`; return a `speedy_http::Error` instead")]
 macro_rules! ensure {
     ($e: expr, $err: expr) => {
         $e.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, $err))?
@@ -17,7 +37,6 @@ static HANDLE_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicU
 
 #[derive(Copy, Clone, Debug)]
 pub struct RequestHandle<T = ()> {
-    #[allow(dead_code)]
     id: usize,
     data: T,
 }
@@ -28,6 +47,9 @@ impl<T> RequestHandle<T> {
             data,
         }
     }
+    pub fn id(&self) -> usize {
+        self.id
+    }
     pub fn into_data(self) -> T {
         self.data
     }
@@ -0,0 +1,266 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket holding up to `capacity` tokens, refilled at `per_second` tokens a second.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    pub capacity: f64,
+    pub per_second: f64,
+}
+
+/// Request budgets enforced before requests are written.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    /// Budget shared by every request.
+    pub global: Option<Budget>,
+    /// Budgets for requests whose path starts with the given prefix. A request takes tokens
+    /// from every budget it matches.
+    pub routes: Vec<(String, Budget)>,
+}
+
+struct Bucket {
+    budget: Budget,
+    tokens: f64,
+    updated: Instant,
+}
+impl Bucket {
+    fn new(budget: Budget, now: Instant) -> Self {
+        Self {
+            tokens: budget.capacity,
+            budget,
+            updated: now,
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        // `updated` lies ahead while the bucket is held.
+        if now <= self.updated {
+            return;
+        }
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.budget.per_second).min(self.budget.capacity);
+        self.updated = now;
+    }
+    /// When `weight` tokens will be available. Weights above the capacity wait for a full
+    /// bucket, so they are not held back forever.
+    fn available_at(&self, weight: f64, now: Instant) -> Instant {
+        let missing = weight.min(self.budget.capacity) - self.tokens;
+        if missing <= 0.0 {
+            now
+        } else if self.budget.per_second > 0.0 {
+            now + Duration::from_secs_f64(missing / self.budget.per_second)
+        } else {
+            now + Duration::from_secs(3600)
+        }
+    }
+    fn take(&mut self, weight: f64) {
+        self.tokens -= weight.min(self.budget.capacity);
+    }
+}
+
+pub(crate) struct RateLimiter {
+    global: Option<Bucket>,
+    routes: Vec<(String, Bucket)>,
+}
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            global: config.global.clone().map(|x| Bucket::new(x, now)),
+            routes: config
+                .routes
+                .iter()
+                .map(|(prefix, budget)| (prefix.clone(), Bucket::new(budget.clone(), now)))
+                .collect(),
+        }
+    }
+    fn buckets<'a>(&'a mut self, path: &'a str) -> impl Iterator<Item = &'a mut Bucket> + 'a {
+        self.global.iter_mut().chain(
+            self.routes
+                .iter_mut()
+                .filter(move |x| path.starts_with(x.0.as_str()))
+                .map(|x| &mut x.1),
+        )
+    }
+    /// Takes `weight` tokens from every budget `path` falls under, or returns when they will
+    /// all have enough.
+    pub fn try_acquire(&mut self, path: &str, weight: u32, now: Instant) -> Result<(), Instant> {
+        let weight = weight as f64;
+        let mut ready_at = now;
+        for bucket in self.buckets(path) {
+            bucket.refill(now);
+            ready_at = ready_at.max(bucket.available_at(weight, now));
+        }
+        if ready_at > now {
+            return Err(ready_at);
+        }
+        self.buckets(path).for_each(|x| x.take(weight));
+        Ok(())
+    }
+    /// When the global budget holds a token again. Route budgets depend on the request, so
+    /// they are only checked once it is dispatched.
+    pub fn global_ready_at(&mut self, now: Instant) -> Instant {
+        match self.global.as_mut() {
+            Some(bucket) => {
+                bucket.refill(now);
+                bucket.available_at(1.0, now)
+            }
+            None => now,
+        }
+    }
+    /// Empties every budget and keeps it from refilling until `until`.
+    pub fn hold_until(&mut self, until: Instant) {
+        for bucket in self
+            .global
+            .iter_mut()
+            .chain(self.routes.iter_mut().map(|x| &mut x.1))
+        {
+            bucket.tokens = 0.0;
+            bucket.updated = bucket.updated.max(until);
+        }
+    }
+    /// Lowers the global budget to what the server reports is left.
+    pub fn sync_remaining(&mut self, remaining: f64) {
+        if let Some(bucket) = self.global.as_mut() {
+            bucket.tokens = bucket.tokens.min(remaining);
+        }
+    }
+    /// Tokens left in the global budget at `now`.
+    pub fn global_tokens(&mut self, now: Instant) -> Option<f64> {
+        self.global.as_mut().map(|x| {
+            x.refill(now);
+            x.tokens
+        })
+    }
+    /// Tokens left in the emptiest route budget at `now`.
+    pub fn route_tokens(&mut self, now: Instant) -> Option<f64> {
+        self.routes
+            .iter_mut()
+            .map(|x| {
+                x.1.refill(now);
+                x.1.tokens
+            })
+            .fold(None, |min, x| Some(min.map_or(x, |min: f64| min.min(x))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The time on tokio's clock, which the tests pause and advance.
+    fn now() -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+    fn budget(capacity: f64, per_second: f64) -> Budget {
+        Budget {
+            capacity,
+            per_second,
+        }
+    }
+    async fn advance_ms(ms: u64) {
+        tokio::time::advance(Duration::from_millis(ms)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_at_its_rate() {
+        let mut bucket = Bucket::new(budget(2.0, 10.0), now());
+        bucket.take(2.0);
+        assert_eq!(
+            bucket.available_at(1.0, now()),
+            now() + Duration::from_millis(100)
+        );
+        advance_ms(50).await;
+        bucket.refill(now());
+        assert!((bucket.tokens - 0.5).abs() < 1e-9);
+        advance_ms(50).await;
+        bucket.refill(now());
+        assert_eq!(bucket.available_at(1.0, now()), now());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refill_is_capped_at_burst() {
+        let mut bucket = Bucket::new(budget(3.0, 10.0), now());
+        advance_ms(10_000).await;
+        bucket.refill(now());
+        assert_eq!(bucket.tokens, 3.0);
+        // A weight above the capacity waits for a full bucket and empties it.
+        bucket.take(5.0);
+        assert_eq!(bucket.tokens, 0.0);
+        assert_eq!(
+            bucket.available_at(5.0, now()),
+            now() + Duration::from_millis(300)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limiter_allows_burst_then_paces() {
+        let config = RateLimitConfig {
+            global: Some(budget(3.0, 10.0)),
+            routes: vec![],
+        };
+        let mut limiter = RateLimiter::new(&config, now());
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire("/", 1, now()), Ok(()));
+        }
+        let ready_at = limiter.try_acquire("/", 1, now()).unwrap_err();
+        assert_eq!(ready_at, now() + Duration::from_millis(100));
+        advance_ms(99).await;
+        assert!(limiter.try_acquire("/", 1, now()).is_err());
+        advance_ms(1).await;
+        assert_eq!(limiter.try_acquire("/", 1, now()), Ok(()));
+        assert!(limiter.try_acquire("/", 1, now()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn route_and_global_budgets_both_apply() {
+        let config = RateLimitConfig {
+            global: Some(budget(3.0, 1.0)),
+            routes: vec![("/orders".into(), budget(1.0, 10.0))],
+        };
+        let mut limiter = RateLimiter::new(&config, now());
+        assert_eq!(limiter.try_acquire("/orders/1", 1, now()), Ok(()));
+        // The route budget is empty, while other paths still have global tokens.
+        let ready_at = limiter.try_acquire("/orders/2", 1, now()).unwrap_err();
+        assert_eq!(ready_at, now() + Duration::from_millis(100));
+        assert_eq!(limiter.global_tokens(now()), Some(2.0));
+        assert_eq!(limiter.try_acquire("/account", 1, now()), Ok(()));
+        assert_eq!(limiter.try_acquire("/account", 1, now()), Ok(()));
+        assert_eq!(limiter.route_tokens(now()), Some(0.0));
+        // Now the route budget refilled, but the global one holds the request back.
+        advance_ms(100).await;
+        let ready_at = limiter.try_acquire("/orders/2", 1, now()).unwrap_err();
+        assert!(ready_at > now() + Duration::from_millis(800));
+        assert_eq!(limiter.route_tokens(now()), Some(1.0));
+        advance_ms(900).await;
+        assert_eq!(limiter.try_acquire("/orders/2", 1, now()), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reported_tokens_include_the_refill() {
+        let config = RateLimitConfig {
+            global: Some(budget(4.0, 10.0)),
+            routes: vec![("/".into(), budget(2.0, 10.0))],
+        };
+        let mut limiter = RateLimiter::new(&config, now());
+        assert_eq!(limiter.try_acquire("/", 2, now()), Ok(()));
+        assert_eq!(limiter.global_tokens(now()), Some(2.0));
+        assert_eq!(limiter.route_tokens(now()), Some(0.0));
+        // Nothing was acquired since, but the budgets refilled all the same.
+        advance_ms(100).await;
+        assert_eq!(limiter.global_tokens(now()), Some(3.0));
+        assert_eq!(limiter.route_tokens(now()), Some(1.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_budgets_stay_empty_until_released() {
+        let config = RateLimitConfig {
+            global: Some(budget(5.0, 10.0)),
+            routes: vec![("/".into(), budget(5.0, 10.0))],
+        };
+        let mut limiter = RateLimiter::new(&config, now());
+        limiter.hold_until(now() + Duration::from_secs(1));
+        advance_ms(999).await;
+        assert!(limiter.try_acquire("/", 1, now()).is_err());
+        assert_eq!(limiter.global_tokens(now()), Some(0.0));
+        advance_ms(101).await;
+        assert_eq!(limiter.try_acquire("/", 1, now()), Ok(()));
+    }
+}
@@ -0,0 +1,367 @@
+use crate::connect::{Connect, ConnectContext};
+use crate::pool::Finished;
+use crate::{
+    Error, Hedge, HttpClientPool, HttpClientPoolConfig, Rejected, RequestHandle, RequestOptions,
+};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{Future, Stream};
+use http::header::HOST;
+use http::uri::{Authority, Scheme};
+use http::Request;
+use std::collections::HashMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::*;
+
+/// The scheme and authority requests are routed by. The default port of the scheme is left
+/// out of the authority, so `http://a` and `http://a:80` are the same origin.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Origin {
+    pub scheme: Scheme,
+    pub authority: Authority,
+}
+impl Origin {
+    pub fn new(scheme: Scheme, authority: Authority) -> Self {
+        let default_port = if scheme == Scheme::HTTPS {
+            Some(443)
+        } else if scheme == Scheme::HTTP {
+            Some(80)
+        } else {
+            None
+        };
+        let authority = match authority.port_u16() {
+            Some(port) if Some(port) == default_port => match authority.host().parse() {
+                Ok(host) => host,
+                Err(_) => authority,
+            },
+            _ => authority,
+        };
+        Self { scheme, authority }
+    }
+    /// The origin of the request's URI. Requests in origin-form are routed by their `Host`
+    /// header, as `http`.
+    pub fn of<Buf>(request: &Request<Buf>) -> Option<Self> {
+        Self::with_default_scheme(request, Scheme::HTTP)
+    }
+    /// Like `of`, taking requests in origin-form to be for `scheme`.
+    pub(crate) fn with_default_scheme<Buf>(request: &Request<Buf>, scheme: Scheme) -> Option<Self> {
+        let uri = request.uri();
+        let authority = match uri.authority() {
+            Some(authority) => authority.clone(),
+            None => request.headers().get(HOST)?.to_str().ok()?.parse().ok()?,
+        };
+        let scheme = uri.scheme().cloned().unwrap_or(scheme);
+        Some(Self::new(scheme, authority))
+    }
+}
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.authority)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MultiOriginPoolConfig {
+    /// Settings for the pool of every origin not listed in `origins`.
+    pub default: HttpClientPoolConfig,
+    /// Settings for specific origins, such as their connection count and rate limits.
+    pub origins: HashMap<Origin, HttpClientPoolConfig>,
+    /// Most origin pools kept. Beyond it, the least recently used idle pool is dropped with its
+    /// connections when a new origin comes up. Pools with requests in flight are kept, so the
+    /// count can still exceed it.
+    pub max_pools: Option<usize>,
+}
+
+const NO_ORIGIN: Error = Error::InvalidRequest("No origin to route to");
+
+/// Adapts a connector closure that only needs the origin.
+struct OriginFn<Func>(Func);
+
+impl<Channel, Func, Fut> Connect<Channel> for OriginFn<Func>
+where
+    Func: Fn(&Origin) -> Fut + Send,
+    Fut: Future<Output = std::io::Result<Channel>> + Send + 'static,
+{
+    fn connect(&self, cx: &ConnectContext<'_>) -> BoxFuture<'static, std::io::Result<Channel>> {
+        match cx.origin {
+            Some(origin) => Box::pin((self.0)(origin)),
+            None => Box::pin(async {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "no origin to connect to",
+                ))
+            }),
+        }
+    }
+}
+
+/// The connector of a `MultiOriginPool`, shared by the pools of all origins.
+struct Shared<Channel>(Arc<dyn Connect<Channel> + Sync>);
+
+impl<Channel> Connect<Channel> for Shared<Channel> {
+    fn connect(&self, cx: &ConnectContext<'_>) -> BoxFuture<'static, std::io::Result<Channel>> {
+        self.0.connect(cx)
+    }
+}
+
+/// Routes requests by scheme and authority to one `HttpClientPool` per origin. The pool of
+/// an origin is created on its first request, connecting through the connector given here.
+pub struct MultiOriginPool<Channel, Buf = Bytes, T = ()> {
+    pools: HashMap<Origin, HttpClientPool<Channel, Buf, T>>,
+    /// Origins in creation order, so that `poll_response` polls them fairly.
+    order: Vec<Origin>,
+    /// When each pool was last asked for, to pick which to evict.
+    last_used: HashMap<Origin, Instant>,
+    next_poll: usize,
+    connector: Arc<dyn Connect<Channel> + Sync>,
+    config: MultiOriginPoolConfig,
+    waker: Option<Waker>,
+    shutdown: bool,
+}
+
+impl<
+        Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        Buf: bytes::Buf + Clone,
+        T: Clone,
+    > MultiOriginPool<Channel, Buf, T>
+{
+    pub fn new<Func, Fut>(connector: Func, config: MultiOriginPoolConfig) -> Self
+    where
+        Func: Fn(&Origin) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<Channel>> + Send + 'static,
+    {
+        Self::with_connector(OriginFn(connector), config)
+    }
+    /// Like `new`, with a connector that also learns the slot and previous failures. The
+    /// context always carries the origin.
+    pub fn with_connector(
+        connector: impl Connect<Channel> + Sync + 'static,
+        config: MultiOriginPoolConfig,
+    ) -> Self {
+        Self {
+            pools: Default::default(),
+            order: vec![],
+            last_used: Default::default(),
+            next_poll: 0,
+            connector: Arc::new(connector),
+            config,
+            waker: None,
+            shutdown: false,
+        }
+    }
+    /// The pool serving `origin`, creating it if needed.
+    pub fn pool_mut(&mut self, origin: &Origin) -> &mut HttpClientPool<Channel, Buf, T> {
+        self.last_used.insert(origin.clone(), Instant::now());
+        if !self.pools.contains_key(origin) {
+            if matches!(self.config.max_pools, Some(max) if self.pools.len() >= max) {
+                self.evict_idle();
+            }
+            let mut config = self
+                .config
+                .origins
+                .get(origin)
+                .unwrap_or(&self.config.default)
+                .clone();
+            config.client.default_authority = Some(origin.authority.clone());
+            config.origin = Some(origin.clone());
+            let mut pool = HttpClientPool::with_connector(Shared(self.connector.clone()), config);
+            if self.shutdown {
+                pool.shutdown();
+            }
+            self.pools.insert(origin.clone(), pool);
+            self.order.push(origin.clone());
+        }
+        self.pools.get_mut(origin).unwrap()
+    }
+    /// Drops the least recently used pool without requests in flight, if there is one.
+    fn evict_idle(&mut self) {
+        let pools = &self.pools;
+        let evicted = self
+            .order
+            .iter()
+            .filter(|x| pools[*x].is_idle())
+            .min_by_key(|x| self.last_used.get(*x))
+            .cloned();
+        if let Some(origin) = evicted {
+            debug!("Dropping idle pool of {}", origin);
+            self.pools.remove(&origin);
+            self.last_used.remove(&origin);
+            self.order.retain(|x| *x != origin);
+        }
+    }
+    pub fn pool(&self, origin: &Origin) -> Option<&HttpClientPool<Channel, Buf, T>> {
+        self.pools.get(origin)
+    }
+    pub fn pools(&self) -> impl Iterator<Item = (&Origin, &HttpClientPool<Channel, Buf, T>)> {
+        self.pools.iter()
+    }
+    /// The pool for the request's origin, or `None` if it has none.
+    fn route(&mut self, request: &Request<Buf>) -> Option<&mut HttpClientPool<Channel, Buf, T>> {
+        let origin = Origin::of(request)?;
+        // Pools created after this are polled only once the task wakes up again.
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Some(self.pool_mut(&origin))
+    }
+    #[allow(clippy::result_large_err)]
+    pub fn request(
+        &mut self,
+        request: Request<Buf>,
+        data: T,
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        self.request_with_options(request, data, Default::default())
+    }
+    #[allow(clippy::result_large_err)]
+    pub fn request_with_options(
+        &mut self,
+        request: Request<Buf>,
+        data: T,
+        options: RequestOptions,
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        match self.route(&request) {
+            Some(pool) => pool.request_with_options(request, data, options),
+            None => Err(Rejected::new(NO_ORIGIN, request)),
+        }
+    }
+    #[allow(clippy::result_large_err)]
+    pub fn request_hedged(
+        &mut self,
+        request: Request<Buf>,
+        data: T,
+        hedge: Hedge,
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        match self.route(&request) {
+            Some(pool) => pool.request_hedged(request, data, hedge),
+            None => Err(Rejected::new(NO_ORIGIN, request)),
+        }
+    }
+    /// Returns the next finished request of any origin.
+    pub fn poll_response(&mut self, cx: &mut Context) -> Poll<Finished<T>> {
+        if !matches!(&self.waker, Some(x) if x.will_wake(cx.waker())) {
+            self.waker = Some(cx.waker().clone());
+        }
+        let len = self.order.len();
+        for i in 0..len {
+            let origin = &self.order[(self.next_poll + i) % len];
+            let pool = self.pools.get_mut(origin).unwrap();
+            if let Poll::Ready(finished) = pool.poll_response(cx) {
+                self.next_poll = (self.next_poll + i + 1) % len;
+                return Poll::Ready(finished);
+            }
+        }
+        Poll::Pending
+    }
+    /// Stops taking new requests on every origin.
+    pub fn shutdown(&mut self) {
+        self.shutdown = true;
+        self.pools.values_mut().for_each(|x| x.shutdown());
+    }
+    pub fn is_idle(&self) -> bool {
+        self.pools.values().all(|x| x.is_idle())
+    }
+}
+
+impl<Channel, Buf, T> Stream for MultiOriginPool<Channel, Buf, T>
+where
+    Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Buf: bytes::Buf + Clone + Unpin,
+    T: Clone + Unpin,
+{
+    type Item = Finished<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Poll::Ready(finished) = this.poll_response(cx) {
+            return Poll::Ready(Some(finished));
+        }
+        if this.shutdown && this.is_idle() {
+            this.pools.clear();
+            this.order.clear();
+            this.last_used.clear();
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use tokio::io::DuplexStream;
+
+    fn origin(uri: &str) -> Origin {
+        let request = Request::get(uri).body(()).unwrap();
+        Origin::of(&request).unwrap()
+    }
+
+    #[test]
+    fn default_ports_are_normalised() {
+        let same = vec![
+            ("http://a", "http://a:80"),
+            ("https://a", "https://a:443"),
+            ("http://A:80", "http://a"),
+            ("http://[::1]:80", "http://[::1]"),
+        ];
+        for (x, y) in same {
+            assert_eq!(origin(x), origin(y), "{} {}", x, y);
+            let set: HashSet<_> = vec![origin(x), origin(y)].into_iter().collect();
+            assert_eq!(set.len(), 1, "{} {}", x, y);
+        }
+        let different = vec![
+            ("http://a", "https://a"),
+            ("http://a:443", "http://a"),
+            ("https://a:80", "https://a"),
+            ("http://a:8080", "http://a"),
+        ];
+        for (x, y) in different {
+            assert_ne!(origin(x), origin(y), "{} {}", x, y);
+        }
+        assert_eq!(origin("http://a:80").to_string(), "http://a");
+        let request = Request::get("/").header(HOST, "a:80").body(()).unwrap();
+        assert_eq!(Origin::of(&request), Some(origin("http://a")));
+    }
+
+    /// A pool whose connections never come up, so requests stay in flight.
+    fn never_connecting(max_pools: usize) -> MultiOriginPool<DuplexStream> {
+        MultiOriginPool::new(
+            |_: &Origin| futures::future::pending(),
+            MultiOriginPoolConfig {
+                max_pools: Some(max_pools),
+                ..Default::default()
+            },
+        )
+    }
+    fn pooled(pool: &MultiOriginPool<DuplexStream>) -> Vec<String> {
+        let mut origins: Vec<_> = pool.pools().map(|x| x.0.to_string()).collect();
+        origins.sort();
+        origins
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_idle_pool_is_evicted() {
+        let mut pool = never_connecting(2);
+        pool.pool_mut(&origin("http://a"));
+        pool.pool_mut(&origin("http://b"));
+        pool.pool_mut(&origin("http://a"));
+        pool.pool_mut(&origin("http://c"));
+        assert_eq!(pooled(&pool), ["http://a", "http://c"]);
+    }
+
+    #[tokio::test]
+    async fn busy_pools_are_not_evicted() {
+        let mut pool = never_connecting(1);
+        pool.request(Request::get("http://a/").body(Bytes::new()).unwrap(), ())
+            .unwrap();
+        pool.pool_mut(&origin("http://b"));
+        assert_eq!(pooled(&pool), ["http://a", "http://b"]);
+        pool.pool_mut(&origin("http://c"));
+        assert_eq!(pooled(&pool), ["http://a", "http://c"]);
+    }
+}
@@ -1,99 +1,422 @@
+use crate::balance::{BalanceStrategy, Balancer, ConnectionInfo, Rng};
+use crate::connect::{Connect, ConnectContext, FnConnector};
+use crate::future::ResponseSender;
+use crate::limit::{RateLimitConfig, RateLimiter};
 use crate::stat::{ConnectionStatistics, ConnectionStatisticsEntry};
-use crate::{HttpClient, RequestHandle};
+use crate::throttle::{self, ThrottlePolicy};
+use crate::timer::{self, Timer};
+use crate::{Error, HttpClient, HttpClientConfig, Origin, Rejected, RequestHandle, ResponseFuture};
 use futures::future::BoxFuture;
-use futures::{Future, FutureExt};
-use http::Response;
-use std::task::{Context, Poll};
+use futures::{Future, FutureExt, Sink, Stream};
+use http::uri::Scheme;
+use http::{Method, Response};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::*;
 #[derive(Clone)]
 pub struct HttpClientPoolConfig {
     pub maintain_size: Option<usize>,
     pub max_conv_per_channel: usize,
+    /// Default time a request may take from submission until its response has arrived.
+    pub request_timeout: Option<Duration>,
+    pub client: HttpClientConfig,
+    pub retry: RetryPolicy,
+    pub connect: ConnectPolicy,
+    pub balancer: BalanceStrategy,
+    pub prune: PrunePolicy,
+    pub rate_limit: RateLimitConfig,
+    pub throttle: ThrottlePolicy,
+    /// The origin the pool connects to, passed on to its connector. Requests for another
+    /// origin are rejected. Left unset, the pool serves the origin of its first request, or
+    /// of `client.default_authority` for a first request without one. It also serves as the
+    /// `default_authority` when that is unset.
+    pub origin: Option<Origin>,
 }
-type PendingQueue<T, Buf> = std::collections::VecDeque<(RequestHandle<T>, http::Request<Buf>)>;
+impl Default for HttpClientPoolConfig {
+    fn default() -> Self {
+        Self {
+            maintain_size: None,
+            max_conv_per_channel: 1,
+            request_timeout: None,
+            client: Default::default(),
+            retry: Default::default(),
+            connect: Default::default(),
+            balancer: Default::default(),
+            prune: Default::default(),
+            rate_limit: Default::default(),
+            throttle: Default::default(),
+            origin: None,
+        }
+    }
+}
+/// What to do with requests left unanswered when their connection breaks.
+#[derive(Clone, Default)]
+pub struct RetryPolicy {
+    /// How many times an idempotent request is put back into the pending queue after its
+    /// connection broke. Zero disables replay.
+    pub max_replays: usize,
+}
+/// How the pool paces connection attempts to a failing server.
+#[derive(Clone)]
+pub struct ConnectPolicy {
+    /// Connect attempts taking longer than this fail with `io::ErrorKind::TimedOut`.
+    pub timeout: Option<Duration>,
+    /// Delay before connecting again after a failed attempt. It doubles with every consecutive
+    /// failure, up to `max_backoff`, and is randomly shortened by up to half.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive failures after which the circuit opens. Zero disables the circuit breaker.
+    pub failure_threshold: usize,
+    /// How long the circuit stays open before a single trial connection is attempted.
+    pub open_duration: Duration,
+}
+impl Default for ConnectPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            failure_threshold: 0,
+            open_duration: Duration::from_secs(5),
+        }
+    }
+}
+/// How the pool ranks its connections by latency and replaces the slowest ones.
+#[derive(Clone)]
+pub struct PrunePolicy {
+    /// How often connections are ranked. `None` disables ranking and pruning.
+    pub interval: Option<Duration>,
+    /// Share of the ranked connections, slowest first, retired at every ranking. Retired
+    /// connections finish their requests and are replaced through the builder.
+    pub slowest_fraction: f64,
+    /// Responses a connection must have completed before it is ranked.
+    pub min_samples: usize,
+}
+impl Default for PrunePolicy {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            slowest_fraction: 0.1,
+            min_samples: 20,
+        }
+    }
+}
+/// State of the pool's circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Connections are made as needed.
+    Closed,
+    /// Connecting failed `failure_threshold` times in a row. Requests that no connection can
+    /// take fail with `Error::CircuitOpen`.
+    Open,
+    /// The open period is over and a trial connection decides whether the circuit closes.
+    HalfOpen,
+}
+/// Per-request settings for `HttpClientPool::request_with_options`.
+#[derive(Clone, Default)]
+pub struct RequestOptions {
+    /// Overrides `HttpClientPoolConfig::request_timeout` for this request.
+    pub deadline: Option<Instant>,
+    /// How long the request may wait for a connection. Once exceeded it is never written
+    /// and fails with `Error::Expired`.
+    pub ttl: Option<Duration>,
+    /// Whether the request is safe to replay. Defaults to whether its method is idempotent.
+    pub idempotent: Option<bool>,
+    /// Tokens the request takes from each rate limit budget it falls under. Defaults to 1.
+    pub weight: Option<u32>,
+}
+struct PendingRequest<T, Buf> {
+    handle: RequestHandle<T>,
+    request: http::Request<Buf>,
+    deadline: Option<Instant>,
+    expires: Option<Instant>,
+    idempotent: bool,
+    replays: usize,
+    weight: u32,
+    /// An extra copy of a hedged request, rather than the original.
+    hedge_copy: bool,
+}
+impl<T: Clone, Buf: Clone> PendingRequest<T, Buf> {
+    /// Copies everything but the request extensions.
+    fn replay_copy(&self) -> Self {
+        let mut request = http::Request::new(self.request.body().clone());
+        *request.method_mut() = self.request.method().clone();
+        *request.uri_mut() = self.request.uri().clone();
+        *request.version_mut() = self.request.version();
+        *request.headers_mut() = self.request.headers().clone();
+        Self {
+            handle: self.handle.clone(),
+            request,
+            deadline: self.deadline,
+            expires: self.expires,
+            idempotent: self.idempotent,
+            replays: self.replays,
+            weight: self.weight,
+            hedge_copy: self.hedge_copy,
+        }
+    }
+}
+impl<T, Buf> PendingRequest<T, Buf> {
+    fn next_expiry(&self) -> Option<Instant> {
+        match (self.deadline, self.expires) {
+            (Some(deadline), Some(expires)) => Some(deadline.min(expires)),
+            (deadline, expires) => deadline.or(expires),
+        }
+    }
+    /// The error this request fails with if it may no longer be sent at `now`.
+    fn check_expired(&self, now: Instant) -> Option<Error> {
+        if matches!(self.expires, Some(x) if x <= now) {
+            Some(Error::Expired)
+        } else if matches!(self.deadline, Some(x) if x <= now) {
+            Some(Error::Timeout)
+        } else {
+            None
+        }
+    }
+}
+/// How `HttpClientPool::request_hedged` duplicates a request.
+#[derive(Debug, Clone, Copy)]
+pub enum Hedge {
+    /// Sends this many copies at once, the original included.
+    Fanout(usize),
+    /// Sends a second copy if no response arrived within the delay.
+    Delay(Duration),
+}
+struct HedgeState<T, Buf> {
+    /// The client the original was written to, once it was.
+    primary: Option<usize>,
+    /// Clients a copy was written to, the original included, so that no client gets two.
+    clients: Vec<usize>,
+    /// Copies not finished yet, the original included.
+    outstanding: usize,
+    copies_sent: usize,
+    delivered: bool,
+    delayed: Option<(Instant, PendingRequest<T, Buf>)>,
+}
+impl<T, Buf> Default for HedgeState<T, Buf> {
+    fn default() -> Self {
+        Self {
+            primary: None,
+            clients: vec![],
+            outstanding: 1,
+            copies_sent: 0,
+            delivered: false,
+            delayed: None,
+        }
+    }
+}
+type PendingQueue<T, Buf> = std::collections::VecDeque<PendingRequest<T, Buf>>;
+const OTHER_ORIGIN: Error = Error::InvalidRequest("Request is for another origin than the pool");
+pub(crate) type Finished<T> = (RequestHandle<T>, crate::Result<Response<bytes::Bytes>>);
+type FinishedQueue<T> = std::collections::VecDeque<Finished<T>>;
 struct ClientSection<Channel, Buf, T> {
     clients: Vec<HttpClient<Channel, Buf, T>>,
-    last_client: usize,
+    balancer: Box<dyn Balancer>,
+    /// Scratch space for `select`: the clients taking requests, and what the balancer sees.
+    candidates: Vec<usize>,
+    infos: Vec<ConnectionInfo>,
     config: HttpClientPoolConfig,
 }
 impl<Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static, Buf: bytes::Buf, T: Clone>
     ClientSection<Channel, Buf, T>
 {
-    fn get_client_mut(&mut self) -> Option<&mut HttpClient<Channel, Buf, T>> {
-        if self.last_client >= self.clients.len() {
-            self.last_client = 0;
-        }
-        let mut client = None;
-        let total_len = self.clients.len();
-
-        while self.last_client < total_len {
-            let id = self.last_client;
-            self.last_client += 1;
-            let c = &self.clients[id];
-            if c.conn.can_write_head() && c.queue_len() < self.config.max_conv_per_channel {
-                client = Some(id);
-                break;
+    /// Picks a client for a request with `method`, never one of the client ids in `exclude`.
+    /// Only clients that can take the request right now are offered to the balancer.
+    fn select(&mut self, method: &Method, exclude: &[usize]) -> Option<usize> {
+        self.candidates.clear();
+        self.infos.clear();
+        for (i, client) in self.clients.iter().enumerate() {
+            if !client.can_take(method) || exclude.contains(&client.get_client_id()) {
+                continue;
             }
+            self.candidates.push(i);
+            self.infos.push(ConnectionInfo {
+                client_id: client.get_client_id(),
+                queue_len: client.queue_len(),
+                has_slot: self.has_slot(client, method),
+                age: client.age(),
+                latency: client.latency(),
+            });
         }
-        if client.is_none() {
-            client = self
-                .clients
-                .iter()
-                .enumerate()
-                .min_by_key(|x| x.1.queue_len())
-                .map(|x| x.0);
+        if self.infos.is_empty() {
+            return None;
         }
-        let clients = &mut self.clients;
-        client.map(move |i| &mut clients[i])
+        let selected = self.balancer.select(&self.infos)?;
+        self.candidates.get(selected).copied()
+    }
+    fn has_slot(&self, client: &HttpClient<Channel, Buf, T>, method: &Method) -> bool {
+        client.can_take(method) && client.queue_len() < self.config.max_conv_per_channel
     }
+    /// Whether some connection has fewer than `max_conv_per_channel` requests in flight. Which
+    /// methods it takes depends on whether HEAD requests are in flight.
+    fn has_free_slot(&self) -> bool {
+        self.clients
+            .iter()
+            .any(|x| self.has_slot(x, &Method::GET) || self.has_slot(x, &Method::HEAD))
+    }
+}
+/// What `HttpClientPool::ready` and `wait_connected` found when they returned.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolReadiness {
+    /// Connections able to take requests.
+    pub connected: usize,
+    /// Connect attempts that failed while waiting.
+    pub failed_attempts: usize,
+}
+/// A connection's place in the latency ranking.
+#[derive(Debug, Clone)]
+pub struct ConnectionRank {
+    pub client_id: usize,
+    /// Moving average of the time from writing a request until its response completed.
+    pub latency: Duration,
+    pub response_count: usize,
 }
 pub struct HttpClientPoolStats {
     pub current_stat: ConnectionStatistics,
     pub history_stats: Vec<ConnectionStatisticsEntry>,
     pub request_on_channel: Vec<usize>,
+    /// Connections from fastest to slowest, as of the last ranking.
+    pub connection_ranking: Vec<ConnectionRank>,
 }
 
+/// Request bodies have to be `Clone`: idempotent requests are copied for replay and hedged
+/// requests for their extra copies. This is a breaking change for pools over bodies that are
+/// only `bytes::Buf`.
 pub struct HttpClientPool<Channel, Buf = bytes::Bytes, T = ()> {
     client_section: ClientSection<Channel, Buf, T>,
-    connecting: Vec<BoxFuture<'static, std::io::Result<Channel>>>,
-    builder: Box<dyn Fn() -> BoxFuture<'static, std::io::Result<Channel>> + Send>,
+    /// Connection attempts in progress, with their slot.
+    connecting: Vec<(usize, BoxFuture<'static, std::io::Result<Channel>>)>,
+    connector: Box<dyn Connect<Channel>>,
+    /// Slot of every client, by client id.
+    slots: HashMap<usize, usize>,
+    last_connect_error: Option<std::io::Error>,
     pending_requests: PendingQueue<T, Buf>,
+    finished: FinishedQueue<T>,
+    /// Copies of idempotent requests on the wire, kept for replay while retry is enabled.
+    sent: HashMap<usize, PendingRequest<T, Buf>>,
+    /// Senders of the futures returned by `send`, by handle id.
+    waiters: HashMap<usize, ResponseSender>,
+    pending_timer: Timer,
+    /// Consecutive failed connect attempts.
+    connect_failures: u32,
+    /// No connection is attempted before this, while backing off.
+    next_connect: Option<Instant>,
+    circuit: CircuitState,
+    circuit_open_until: Instant,
+    connect_timer: Timer,
+    rng: Rng,
+    next_prune: Option<Instant>,
+    prune_timer: Timer,
+    /// Hedged requests with copies still on the wire or waiting to be sent, by handle id.
+    hedges: HashMap<usize, HedgeState<T, Buf>>,
+    hedge_timer: Timer,
+    /// Wakes the pool when an idle connection reaches its keep-alive expiry, so it is replaced
+    /// before the server closes it.
+    keep_alive_timer: Timer,
+    limiter: RateLimiter,
+    /// When the request held back by the rate limiter may go.
+    limited_until: Option<Instant>,
+    limit_timer: Timer,
+    /// Wakes the task in `Sink::poll_ready` once the rate limit or a server pause is over.
+    sink_timer: Timer,
+    /// Dispatch is paused until then, because the server pushed back.
+    paused_until: Option<Instant>,
+    /// Task that last polled for responses, woken when a request is submitted.
+    waker: Option<Waker>,
+    /// Task waiting in `Sink::poll_ready` for a free pipeline slot.
+    sink_waker: Option<Waker>,
+    shutdown: bool,
     config: HttpClientPoolConfig,
     stats: HttpClientPoolStats,
 }
 
-impl<Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static, Buf: bytes::Buf, T: Clone>
-    HttpClientPool<Channel, Buf, T>
+impl<
+        Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        Buf: bytes::Buf + Clone,
+        T: Clone,
+    > HttpClientPool<Channel, Buf, T>
 {
     pub fn new<Func, Fut>(builder: Func, config: HttpClientPoolConfig) -> Self
     where
         Func: Fn() -> Fut + Send + 'static,
         Fut: Future<Output = std::io::Result<Channel>> + Send + 'static,
     {
+        Self::with_connector(FnConnector(builder), config)
+    }
+    pub fn with_connector(
+        connector: impl Connect<Channel> + 'static,
+        mut config: HttpClientPoolConfig,
+    ) -> Self {
+        if let Some(origin) = &config.origin {
+            config
+                .client
+                .default_authority
+                .get_or_insert_with(|| origin.authority.clone());
+        }
         Self {
             client_section: ClientSection {
                 clients: vec![],
-                last_client: 0,
+                balancer: config.balancer.build(),
+                candidates: vec![],
+                infos: vec![],
                 config: config.clone(),
             },
             connecting: vec![],
-            builder: Box::new(move || Box::pin(builder())),
+            connector: Box::new(connector),
+            slots: Default::default(),
+            last_connect_error: None,
             pending_requests: Default::default(),
+            finished: Default::default(),
+            sent: Default::default(),
+            waiters: Default::default(),
+            pending_timer: Default::default(),
+            connect_failures: 0,
+            next_connect: None,
+            circuit: CircuitState::Closed,
+            circuit_open_until: timer::now(),
+            connect_timer: Default::default(),
+            rng: Default::default(),
+            next_prune: None,
+            prune_timer: Default::default(),
+            hedges: Default::default(),
+            hedge_timer: Default::default(),
+            keep_alive_timer: Default::default(),
+            limiter: RateLimiter::new(&config.rate_limit, timer::now()),
+            limited_until: None,
+            limit_timer: Default::default(),
+            sink_timer: Default::default(),
+            paused_until: None,
+            waker: None,
+            sink_waker: None,
+            shutdown: false,
             config,
 
             stats: HttpClientPoolStats {
                 current_stat: Default::default(),
                 history_stats: vec![],
                 request_on_channel: vec![],
+                connection_ranking: vec![],
             },
         }
     }
 
     fn record_status(&mut self) {
+        let now = timer::now();
         self.stats.current_stat.connection_connecting_count = self.connecting.len() as i64;
         self.stats.current_stat.connection_living_count = self.client_section.clients.len() as i64;
         self.stats.current_stat.request_pending_count = self.pending_requests.len() as i64;
+        self.stats.current_stat.rate_limit_tokens =
+            self.limiter.global_tokens(now).map_or(-1, |x| x as i64);
+        self.stats.current_stat.rate_limit_route_tokens =
+            self.limiter.route_tokens(now).map_or(-1, |x| x as i64);
+        self.stats.current_stat.circuit_state = match self.circuit_state() {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        };
         match self.stats.history_stats.last() {
             Some(x) if x.stat == self.stats.current_stat => {}
             _ => self.stats.history_stats.push(ConnectionStatisticsEntry {
@@ -106,15 +429,33 @@ impl<Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static, Buf: bytes::Buf,
     pub fn poll_connecting(&mut self, cx: &mut Context<'_>) {
         let mut i = 0;
         while i < self.connecting.len() {
-            let connecting = &mut self.connecting[i];
+            let (slot, connecting) = &mut self.connecting[i];
+            let slot = *slot;
             match connecting.poll_unpin(cx) {
                 Poll::Ready(Ok(channel)) => {
-                    self.client_section.clients.push(HttpClient::new(channel));
-                    self.connecting.swap_remove(i);
+                    self.connect_failures = 0;
+                    self.next_connect = None;
+                    self.last_connect_error = None;
+                    self.circuit = CircuitState::Closed;
+                    let client = HttpClient::with_config(channel, self.config.client.clone());
+                    self.slots.insert(client.get_client_id(), slot);
+                    self.client_section.clients.push(client);
+                    let _ = self.connecting.swap_remove(i);
                 }
                 Poll::Ready(Err(err)) => {
                     error!("Error while connecting {:?}", err);
-                    self.connecting.swap_remove(i);
+                    self.stats.current_stat.connection_failed_count += 1;
+                    let _ = self.connecting.swap_remove(i);
+                    // With nothing left that could take them, the pending requests would only
+                    // wait for a deadline, so they fail with the cause instead.
+                    if self.connecting.is_empty() && self.usable_count() == 0 {
+                        for request in std::mem::take(&mut self.pending_requests) {
+                            let err = std::io::Error::new(err.kind(), err.to_string());
+                            self.fail_pending(request, Error::Connect(err));
+                        }
+                    }
+                    self.last_connect_error = Some(err);
+                    self.on_connect_failed();
                 }
                 Poll::Pending => {
                     i += 1;
@@ -122,120 +463,1895 @@ impl<Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static, Buf: bytes::Buf,
             }
         }
     }
-    fn make_connection(&mut self) {
+    fn on_connect_failed(&mut self) {
+        self.connect_failures += 1;
+        let now = timer::now();
+        let backoff = self
+            .config
+            .connect
+            .backoff
+            .checked_mul(1 << (self.connect_failures - 1).min(16))
+            .map_or(self.config.connect.max_backoff, |x| {
+                x.min(self.config.connect.max_backoff)
+            });
+        let jitter = backoff.mul_f64(self.rng.unit() / 2.0);
+        self.next_connect = Some(now + backoff - jitter);
+        let threshold = self.config.connect.failure_threshold;
+        if self.circuit == CircuitState::HalfOpen
+            || threshold > 0 && self.connect_failures as usize >= threshold
+        {
+            if self.circuit != CircuitState::Open {
+                warn!(
+                    "Circuit opened after {} failed connect attempts",
+                    self.connect_failures
+                );
+                self.stats.current_stat.circuit_opened_count += 1;
+            }
+            self.circuit = CircuitState::Open;
+            self.circuit_open_until = now + self.config.connect.open_duration;
+        }
+    }
+    pub fn circuit_state(&self) -> CircuitState {
+        match self.circuit {
+            CircuitState::Open if self.circuit_open_until <= timer::now() => CircuitState::HalfOpen,
+            state => state,
+        }
+    }
+    /// Whether a connection may be attempted now, given the backoff and circuit state.
+    fn may_connect(&mut self) -> bool {
+        let now = timer::now();
+        match self.circuit_state() {
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                self.circuit = CircuitState::HalfOpen;
+                self.connecting.is_empty()
+            }
+            CircuitState::Closed => !matches!(self.next_connect, Some(x) if x > now),
+        }
+    }
+    /// Starts a connection attempt, unless backing off or the circuit is open.
+    fn make_connection(&mut self) -> bool {
+        if !self.may_connect() {
+            return false;
+        }
         self.stats.current_stat.connection_new_count += 1;
-        self.connecting.push((self.builder)());
+        let slot = (0..)
+            .find(|x| {
+                !self.slots.values().any(|y| y == x) && !self.connecting.iter().any(|y| y.0 == *x)
+            })
+            .unwrap();
+        let connecting = self.connector.connect(&ConnectContext {
+            origin: self.config.origin.as_ref(),
+            slot,
+            attempt: self.connect_failures + 1,
+            previous_error: self.last_connect_error.as_ref(),
+        });
+        let connecting = match self.config.connect.timeout {
+            Some(timeout) => Box::pin(async move {
+                tokio::time::timeout(timeout, connecting)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "connect timed out",
+                        ))
+                    })
+            }),
+            None => connecting,
+        };
+        self.connecting.push((slot, connecting));
+        true
     }
+    /// Wakes the pool once it may connect again, and fails the pending requests while the
+    /// circuit is open and no connection is left to take them.
+    fn poll_circuit(&mut self, cx: &mut Context<'_>) {
+        let retry_at = match self.circuit {
+            CircuitState::Open => Some(self.circuit_open_until),
+            CircuitState::Closed => self.next_connect,
+            CircuitState::HalfOpen => None,
+        };
+        if let Some(retry_at) = retry_at {
+            let _ = self.connect_timer.poll_at(cx, retry_at);
+        }
+        if self.circuit_state() == CircuitState::Open && self.usable_count() == 0 {
+            for request in std::mem::take(&mut self.pending_requests) {
+                self.fail_pending(request, Error::CircuitOpen);
+            }
+        }
+    }
+    /// Opens connections until `maintain_size` of them can take requests. Connections that are
+    /// winding down because of `Connection: close` or keep-alive limits do not count, so their
+    /// replacements are ready before they close. Pending requests get a connection once the
+    /// backoff is over, even without `maintain_size`.
     pub fn poll_maintain_connection(&mut self) {
-        while self.client_section.clients.len() + self.connecting.len()
-            < self.config.maintain_size.unwrap_or(0)
-        {
-            self.make_connection()
+        if self.shutdown && self.pending_requests.is_empty() {
+            return;
         }
+        self.open_connections(self.config.maintain_size.unwrap_or(0));
+        self.connect_for_pending();
     }
-    fn try_make_request(
-        client: &mut HttpClient<Channel, Buf, T>,
-        handle: RequestHandle<T>,
-        pending: &mut PendingQueue<T, Buf>,
-        request: http::Request<Buf>,
-        stats: &mut HttpClientPoolStats,
-    ) {
-        match client.request_with_handle(request, handle.clone()) {
-            Ok(..) => stats.request_on_channel.push(client.get_client_id()),
-            Err(req) => {
-                warn!("Client to be removed, request pending");
-                pending.push_back((handle, req));
+    /// Opens connections until `target` of them can take requests or are being established.
+    fn open_connections(&mut self, target: usize) {
+        while self.usable_count() + self.connecting.len() < target {
+            if !self.make_connection() {
+                break;
             }
         }
     }
-    pub fn request(&mut self, request: http::Request<Buf>, data: T) -> RequestHandle<T> {
-        let handle = RequestHandle::unique(data.clone());
-        if let Some(client) = self.client_section.get_client_mut() {
-            Self::try_make_request(
-                client,
-                handle.clone(),
-                &mut self.pending_requests,
-                request,
-                &mut self.stats,
-            );
+    fn usable_count(&self) -> usize {
+        self.client_section
+            .clients
+            .iter()
+            .filter(|x| x.accepts_requests())
+            .count()
+    }
+    /// Waits until `min_connections` connections can take requests, opening more as needed.
+    /// Failed attempts are retried.
+    pub async fn ready(&mut self, min_connections: usize) -> PoolReadiness {
+        self.wait_for_connections(min_connections, None).await
+    }
+    /// Waits up to `timeout` for `maintain_size` connections, or one without it. Check
+    /// `PoolReadiness::connected` to see whether they were all established in time.
+    pub async fn wait_connected(&mut self, timeout: Duration) -> PoolReadiness {
+        let min_connections = self.config.maintain_size.unwrap_or(1);
+        self.wait_for_connections(min_connections, Some(timer::now() + timeout))
+            .await
+    }
+    async fn wait_for_connections(
+        &mut self,
+        min_connections: usize,
+        deadline: Option<Instant>,
+    ) -> PoolReadiness {
+        let failed_before = self.stats.current_stat.connection_failed_count;
+        let mut timer = Timer::default();
+        futures::future::poll_fn(|cx| {
+            let failed = self.stats.current_stat.connection_failed_count;
+            self.open_connections(min_connections);
+            self.poll_connecting(cx);
+            self.poll_circuit(cx);
+            self.record_status();
+            let readiness = PoolReadiness {
+                connected: self.usable_count(),
+                failed_attempts: (self.stats.current_stat.connection_failed_count - failed_before)
+                    as usize,
+            };
+            if readiness.connected >= min_connections
+                || matches!(deadline, Some(x) if timer.poll_at(cx, x).is_ready())
+            {
+                return Poll::Ready(readiness);
+            }
+            // Replacements for failed attempts have not been polled yet.
+            if self.stats.current_stat.connection_failed_count != failed && self.may_connect() {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        })
+        .await
+    }
+    /// Ranks the connections taking requests by latency, fastest first. Connections with fewer
+    /// than `PrunePolicy::min_samples` responses are left out.
+    pub fn rank_connections(&mut self) -> &[ConnectionRank] {
+        let min_samples = self.config.prune.min_samples;
+        let mut ranking: Vec<_> = self
+            .client_section
+            .clients
+            .iter()
+            .filter(|x| x.accepts_requests() && x.response_count() >= min_samples)
+            .filter_map(|x| {
+                Some(ConnectionRank {
+                    client_id: x.get_client_id(),
+                    latency: x.latency()?,
+                    response_count: x.response_count(),
+                })
+            })
+            .collect();
+        ranking.sort_by_key(|x| x.latency);
+        self.stats.connection_ranking = ranking;
+        &self.stats.connection_ranking
+    }
+    /// Retires the slowest connections every `PrunePolicy::interval`.
+    fn poll_prune(&mut self, cx: &mut Context<'_>) {
+        let interval = match self.config.prune.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let next_prune = *self
+            .next_prune
+            .get_or_insert_with(|| timer::now() + interval);
+        if self.prune_timer.poll_at(cx, next_prune).is_pending() {
+            return;
+        }
+        let next_prune = timer::now() + interval;
+        self.next_prune = Some(next_prune);
+        let _ = self.prune_timer.poll_at(cx, next_prune);
+
+        let fraction = self.config.prune.slowest_fraction;
+        let ranking = self.rank_connections();
+        if ranking.len() < 2 {
+            return;
+        }
+        // Rounded up, so that small pools still prune their slowest connection.
+        let count = ((ranking.len() as f64 * fraction).ceil() as usize).min(ranking.len());
+        let slowest: Vec<_> = ranking[ranking.len() - count..]
+            .iter()
+            .map(|x| x.client_id)
+            .collect();
+        for client in &mut self.client_section.clients {
+            if slowest.contains(&client.get_client_id()) {
+                debug!(
+                    "Retiring slow client {}, latency {:?}",
+                    client.get_client_id(),
+                    client.latency()
+                );
+                client.retire();
+                self.stats.current_stat.connection_pruned_count += 1;
+            }
+        }
+    }
+    fn try_make_request(&mut self, i: usize, request: PendingRequest<T, Buf>) {
+        let replay = if request.idempotent && self.config.retry.max_replays > 0 {
+            Some(request.replay_copy())
         } else {
-            warn!("No available clients, request pending");
-            self.pending_requests.push_back((handle.clone(), request));
-            if self.client_section.clients.len() + self.connecting.len()
-                < self.config.maintain_size.unwrap_or(usize::MAX)
+            None
+        };
+        let PendingRequest {
+            handle,
+            request,
+            deadline,
+            expires,
+            idempotent,
+            replays,
+            weight,
+            hedge_copy,
+        } = request;
+        let client = &mut self.client_section.clients[i];
+        match client.request_with_deadline(request, handle.clone(), deadline) {
+            Ok(..) => {
+                let client_id = client.get_client_id();
+                self.stats.request_on_channel.push(client_id);
+                if let Some(replay) = replay {
+                    self.sent.insert(handle.id(), replay);
+                }
+                if let Some(state) = self.hedges.get_mut(&handle.id()) {
+                    if !hedge_copy {
+                        state.primary = Some(client_id);
+                    }
+                    state.clients.push(client_id);
+                }
+            }
+            Err(Rejected {
+                error: Error::Busy,
+                request,
+            }) => {
+                // Back to the front, so it keeps its place ahead of later requests.
+                debug!("Client {} busy, request pending", client.get_client_id());
+                self.pending_requests.push_front(PendingRequest {
+                    handle,
+                    request,
+                    deadline,
+                    expires,
+                    idempotent,
+                    replays,
+                    weight,
+                    hedge_copy,
+                });
+            }
+            Err(Rejected { error, .. }) => {
+                self.stats.current_stat.request_failed_count += 1;
+                self.finish(None, handle, Err(error));
+            }
+        }
+    }
+    /// Queues a request for sending. A request that cannot be written, such as one without any
+    /// authority for its `Host` header, or one submitted after `shutdown`, is handed back
+    /// instead.
+    #[allow(clippy::result_large_err)]
+    pub fn request(
+        &mut self,
+        request: http::Request<Buf>,
+        data: T,
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        self.request_with_options(request, data, Default::default())
+    }
+    #[allow(clippy::result_large_err)]
+    pub fn request_with_options(
+        &mut self,
+        request: http::Request<Buf>,
+        data: T,
+        options: RequestOptions,
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        self.submit_request(request, data, options, None)
+    }
+    /// Sends a request on more than one connection and delivers whichever response arrives
+    /// first. The other copies still complete on their connections, keeping the pipelines in
+    /// order, but their responses are dropped. Only use this for requests that are safe to
+    /// process more than once. Copies go to distinct connections, so fewer are sent when not
+    /// enough connections take requests.
+    #[allow(clippy::result_large_err)]
+    pub fn request_hedged(
+        &mut self,
+        request: http::Request<Buf>,
+        data: T,
+        hedge: Hedge,
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        let options = RequestOptions {
+            // Copies share the handle id, which replay bookkeeping relies on being unique.
+            idempotent: Some(false),
+            ..Default::default()
+        };
+        self.submit_request(request, data, options, Some(hedge))
+    }
+    #[allow(clippy::result_large_err)]
+    fn submit_request(
+        &mut self,
+        request: http::Request<Buf>,
+        data: T,
+        options: RequestOptions,
+        hedge: Option<Hedge>,
+    ) -> Result<RequestHandle<T>, Rejected<Buf>> {
+        if self.shutdown {
+            return Err(Rejected::new(Error::Cancelled, request));
+        }
+        if self.circuit_state() == CircuitState::Open && self.usable_count() == 0 {
+            return Err(Rejected::new(Error::CircuitOpen, request));
+        }
+        if self.config.origin.is_none() {
+            let authority = &self.config.client.default_authority;
+            self.config.origin = Origin::of(&request)
+                .or_else(|| Some(Origin::new(Scheme::HTTP, authority.clone()?)));
+            if let Some(origin) = &self.config.origin {
+                debug!("Serving origin {}", origin);
+                self.config
+                    .client
+                    .default_authority
+                    .get_or_insert_with(|| origin.authority.clone());
+            }
+        }
+        if let Some(origin) = &self.config.origin {
+            let target = Origin::with_default_scheme(&request, origin.scheme.clone());
+            if matches!(target, Some(x) if x != *origin) {
+                return Err(Rejected::new(OTHER_ORIGIN, request));
+            }
+        }
+        let request = self.config.client.prepare_request(request)?;
+        let handle = RequestHandle::unique(data);
+        let now = timer::now();
+        let idempotent = options.idempotent.unwrap_or_else(|| {
+            matches!(
+                *request.method(),
+                Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+            )
+        });
+        let request = PendingRequest {
+            handle: handle.clone(),
+            request,
+            deadline: options
+                .deadline
+                .or_else(|| self.config.request_timeout.map(|x| now + x)),
+            expires: options.ttl.map(|x| now + x),
+            idempotent,
+            replays: 0,
+            weight: options.weight.unwrap_or(1),
+            hedge_copy: false,
+        };
+        let hedge = hedge.map(|x| {
+            let copy = PendingRequest {
+                hedge_copy: true,
+                ..request.replay_copy()
+            };
+            (x, copy)
+        });
+        if hedge.is_some() {
+            self.hedges.insert(handle.id(), HedgeState::default());
+        }
+        let selected = self.client_section.select(request.request.method(), &[]);
+        match selected {
+            // Requests already pending go first, so they are not starved of rate limit tokens.
+            Some(i)
+                if self.pending_requests.is_empty()
+                    && !self.is_paused()
+                    && self.acquire(&request) =>
             {
-                self.make_connection()
+                self.try_make_request(i, request);
+            }
+            Some(_) => {
+                debug!("Request pending behind others or rate limited");
+                self.pending_requests.push_back(request);
+            }
+            None => {
+                warn!("No available clients, request pending");
+                self.pending_requests.push_back(request);
+                if self.client_section.clients.len() + self.connecting.len()
+                    < self.config.maintain_size.unwrap_or(usize::MAX)
+                {
+                    self.make_connection();
+                }
+            }
+        }
+        match hedge {
+            Some((Hedge::Fanout(copies), copy)) => {
+                for _ in 1..copies {
+                    if !self.send_hedge(copy.replay_copy()) {
+                        break;
+                    }
+                }
+            }
+            Some((Hedge::Delay(delay), copy)) => {
+                if let Some(state) = self.hedges.get_mut(&handle.id()) {
+                    state.delayed = Some((now + delay, copy));
+                }
             }
+            None => {}
         }
         self.stats.current_stat.request_sent_count += 1;
         self.record_status();
-        handle
+        // The request sits in a write buffer or the pending queue until the pool is polled.
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Ok(handle)
+    }
+    /// Whether the server asked for a pause that is not over yet.
+    fn is_paused(&mut self) -> bool {
+        match self.paused_until {
+            Some(until) if until > timer::now() => {
+                self.limited_until = Some(until);
+                true
+            }
+            _ => {
+                self.paused_until = None;
+                false
+            }
+        }
+    }
+    /// Pauses dispatch when a response signals that the server is throttling, and lowers the
+    /// rate limit budget to what the server reports is left.
+    fn observe_throttle(&mut self, status: http::StatusCode, headers: &http::HeaderMap) {
+        if !self.config.throttle.enabled {
+            return;
+        }
+        let throttle = throttle::inspect(status, headers, &self.config.throttle);
+        if let Some(remaining) = throttle.remaining {
+            self.limiter.sync_remaining(remaining as f64);
+        }
+        if let Some(until) = throttle.pause_until {
+            info!(
+                "Server throttled with {}, pausing dispatch for {:?}",
+                status,
+                until.saturating_duration_since(timer::now())
+            );
+            self.stats.current_stat.response_throttled_count += 1;
+            self.paused_until = Some(self.paused_until.map_or(until, |x| x.max(until)));
+            self.limiter.hold_until(until);
+        }
+    }
+    /// Takes the request's tokens from the rate limiter, or records when they will be there.
+    fn acquire(&mut self, request: &PendingRequest<T, Buf>) -> bool {
+        let path = request.request.uri().path();
+        match self.limiter.try_acquire(path, request.weight, timer::now()) {
+            Ok(()) => true,
+            Err(at) => {
+                self.limited_until = Some(at);
+                false
+            }
+        }
+    }
+    /// Sends another copy of a hedged request, on a connection that has none yet. While the
+    /// original is still pending, the copy queues up behind it, so that it is only placed once
+    /// the original's connection is known.
+    fn send_hedge(&mut self, copy: PendingRequest<T, Buf>) -> bool {
+        let state = match self.hedges.get(&copy.handle.id()) {
+            Some(state) if !state.delivered => state,
+            _ => return false,
+        };
+        let selected = if state.primary.is_some() {
+            match self
+                .client_section
+                .select(copy.request.method(), &state.clients)
+            {
+                Some(i) if !self.is_paused() && self.acquire(&copy) => Some(i),
+                _ => return false,
+            }
+        } else {
+            None
+        };
+        let state = self.hedges.get_mut(&copy.handle.id()).unwrap();
+        state.outstanding += 1;
+        state.copies_sent += 1;
+        self.stats.current_stat.hedge_sent_count += 1;
+        match selected {
+            Some(i) => self.try_make_request(i, copy),
+            None => self.pending_requests.push_back(copy),
+        }
+        true
+    }
+    /// Sends the delayed copies of hedged requests that are still unanswered.
+    fn poll_hedges(&mut self, cx: &mut Context<'_>) {
+        loop {
+            let next = self
+                .hedges
+                .values()
+                .filter_map(|x| x.delayed.as_ref().map(|x| x.0))
+                .min();
+            match next {
+                Some(next) if self.hedge_timer.poll_at(cx, next).is_ready() => {}
+                _ => return,
+            }
+            let now = timer::now();
+            let due: Vec<_> = self
+                .hedges
+                .values_mut()
+                .filter(|x| matches!(x.delayed, Some((at, _)) if at <= now))
+                .filter_map(|x| x.delayed.take())
+                .collect();
+            for (_, copy) in due {
+                self.send_hedge(copy);
+            }
+        }
+    }
+    /// Hands a request's outcome to the caller. Of the copies of a hedged request, only the
+    /// first response gets through, or the last error if every copy failed.
+    fn finish(
+        &mut self,
+        client_id: Option<usize>,
+        handle: RequestHandle<T>,
+        result: crate::Result<Response<bytes::Bytes>>,
+    ) {
+        if let Some(hedge) = self.hedges.get_mut(&handle.id()) {
+            hedge.outstanding -= 1;
+            let deliver = !hedge.delivered && (result.is_ok() || hedge.outstanding == 0);
+            if deliver && result.is_ok() && hedge.copies_sent > 0 {
+                if client_id.is_some() && client_id == hedge.primary {
+                    self.stats.current_stat.hedge_loss_count += 1;
+                } else {
+                    self.stats.current_stat.hedge_win_count += 1;
+                }
+            }
+            hedge.delivered |= deliver;
+            if hedge.outstanding == 0 {
+                self.hedges.remove(&handle.id());
+            }
+            if !deliver {
+                debug!("Discarding outcome of hedged request {}", handle.id());
+                return;
+            }
+        }
+        self.finished.push_back((handle, result));
     }
     pub fn poll_send_request(&mut self) {
+        let now = timer::now();
         for _ in 0..10 {
-            if let Some((handle, request)) = self.pending_requests.pop_front() {
-                if let Some(client) = self.client_section.get_client_mut() {
-                    Self::try_make_request(
-                        client,
-                        handle.clone(),
-                        &mut self.pending_requests,
-                        request,
-                        &mut self.stats,
-                    );
+            if let Some(request) = self.pending_requests.pop_front() {
+                // Copies of a hedged request, including those sent back here by a busy
+                // client, avoid the connections another copy was written to.
+                let hedge = self.hedges.get(&request.handle.id());
+                let exclude = hedge.map_or(&[][..], |x| &x.clients[..]);
+                let selected = self
+                    .client_section
+                    .select(request.request.method(), exclude);
+                // Another copy is on the wire and no other connection can take this one.
+                let redundant = matches!(hedge, Some(x) if x.delivered
+                    || selected.is_none() && !x.clients.is_empty());
+                if let Some(err) = request.check_expired(now) {
+                    self.fail_pending(request, err);
+                } else if redundant {
+                    debug!("Dropping copy of hedged request {}", request.handle.id());
+                    self.finish(None, request.handle, Err(Error::Cancelled));
+                } else if let Some(i) =
+                    selected.filter(|_| !self.is_paused() && self.acquire(&request))
+                {
+                    self.try_make_request(i, request);
                 } else {
-                    self.pending_requests.push_front((handle, request));
+                    self.pending_requests.push_front(request);
                     break;
                 }
             }
         }
     }
-    pub fn poll_response(
+    fn fail_pending(&mut self, request: PendingRequest<T, Buf>, err: Error) {
+        debug!("Pending request dropped: {}", err);
+        if let Error::Expired = err {
+            self.stats.current_stat.request_expired_count += 1;
+        }
+        self.stats.current_stat.request_failed_count += 1;
+        self.finish(None, request.handle, Err(err));
+    }
+    /// Fails pending requests whose deadline or time-to-live passed before a connection could
+    /// take them.
+    fn poll_expire_pending(&mut self, cx: &mut Context<'_>) {
+        let expiry = match self
+            .pending_requests
+            .iter()
+            .filter_map(|x| x.next_expiry())
+            .min()
+        {
+            Some(expiry) => expiry,
+            None => return,
+        };
+        if self.pending_timer.poll_at(cx, expiry).is_pending() {
+            return;
+        }
+        let now = timer::now();
+        let (expired, pending): (PendingQueue<T, Buf>, _) = self
+            .pending_requests
+            .drain(..)
+            .partition(|x| x.check_expired(now).is_some());
+        self.pending_requests = pending;
+        for request in expired {
+            let err = request.check_expired(now).unwrap();
+            self.fail_pending(request, err);
+        }
+    }
+    fn remove_client(&mut self, i: usize) {
+        let mut client = self.client_section.clients.swap_remove(i);
+        self.slots.remove(&client.get_client_id());
+        let mut replays = vec![];
+        for (handle, err) in client.take_unanswered() {
+            let sent = self.sent.remove(&handle.id());
+            let err = match sent {
+                Some(mut request)
+                    if matches!(err, Error::ConnectionReset | Error::NeverWritten)
+                        && request.replays < self.config.retry.max_replays =>
+                {
+                    debug!("Replaying request after: {}", err);
+                    request.replays += 1;
+                    replays.push(request);
+                    continue;
+                }
+                // Neither safe to replay nor replays left: the server may have processed it.
+                _ if matches!(err, Error::ConnectionReset) && self.config.retry.max_replays > 0 => {
+                    Error::OutcomeUnknown
+                }
+                _ => err,
+            };
+            debug!("Request failed on removed client: {}", err);
+            self.stats.current_stat.request_failed_count += 1;
+            self.finish(None, handle, Err(err));
+        }
+        // Replayed requests go out first, in the order they were originally written.
+        for request in replays.into_iter().rev() {
+            self.stats.current_stat.request_replayed_count += 1;
+            self.pending_requests.push_front(request);
+        }
+        self.connect_for_pending();
+    }
+    /// Opens a connection for the pending requests if no connection or attempt is left that
+    /// could take them. Without `maintain_size`, nothing else would.
+    fn connect_for_pending(&mut self) {
+        if !self.pending_requests.is_empty()
+            && self.connecting.is_empty()
+            && self.usable_count() == 0
+        {
+            self.make_connection();
+        }
+    }
+    /// Submits a request and returns a future that resolves with its response. The pool still
+    /// has to be driven by `poll_response` for the future to make progress; `split` hands the
+    /// driving to a `PoolDriver` instead.
+    pub fn send(&mut self, request: http::Request<Buf>) -> ResponseFuture
+    where
+        T: Default,
+    {
+        self.send_with_options(request, Default::default())
+    }
+    pub fn send_with_options(
+        &mut self,
+        request: http::Request<Buf>,
+        options: RequestOptions,
+    ) -> ResponseFuture
+    where
+        T: Default,
+    {
+        let (tx, future) = ResponseFuture::channel();
+        self.submit(request, options, tx);
+        future
+    }
+    pub(crate) fn submit(
         &mut self,
-        cx: &mut Context,
-    ) -> Poll<(RequestHandle<T>, Response<bytes::Bytes>)> {
+        request: http::Request<Buf>,
+        options: RequestOptions,
+        tx: ResponseSender,
+    ) where
+        T: Default,
+    {
+        match self.request_with_options(request, T::default(), options) {
+            Ok(handle) => {
+                self.waiters.insert(handle.id(), tx);
+            }
+            Err(rejected) => {
+                let _ = tx.send(Err(rejected.error));
+            }
+        }
+    }
+    /// Number of futures returned by `send` that have not resolved yet.
+    pub fn waiting_count(&self) -> usize {
+        self.waiters.len()
+    }
+    /// Returns the next finished request that was not submitted through `send`.
+    pub fn poll_response(&mut self, cx: &mut Context) -> Poll<Finished<T>> {
+        loop {
+            let (handle, result) = futures::ready!(self.poll_finished(cx));
+            let waiter = if self.waiters.is_empty() {
+                None
+            } else {
+                self.waiters.remove(&handle.id())
+            };
+            match waiter {
+                Some(tx) => {
+                    let _ = tx.send(result);
+                }
+                None => return Poll::Ready((handle, result)),
+            }
+        }
+    }
+    fn poll_finished(&mut self, cx: &mut Context) -> Poll<Finished<T>> {
+        if !matches!(&self.waker, Some(x) if x.will_wake(cx.waker())) {
+            self.waker = Some(cx.waker().clone());
+        }
+        self.poll_expire_pending(cx);
+        self.poll_prune(cx);
+        self.poll_hedges(cx);
+        self.poll_connecting(cx);
         self.poll_send_request();
-        let mut resp = Poll::Pending;
         let mut i = 0;
-        while i < self.client_section.clients.len() {
+        while i < self.client_section.clients.len() && self.finished.is_empty() {
             let client = &mut self.client_section.clients[i];
             if !client.conn.can_write_head() {
                 warn!("Remove closed client");
-                self.client_section.clients.swap_remove(i);
+                self.remove_client(i);
                 continue;
             }
-            let result = client.poll_response(cx);
-            // match &result {
-            //     Poll::Pending => {}
-            //     Poll::Ready(result) => {
-            //         info!("Poll response result {:?}", result);
-            //     }
-            // }
-
-            match result {
-                Poll::Ready(Some(Ok(r))) => {
-                    resp = Poll::Ready(r);
+            if client.queue_len() == 0 && !client.accepts_requests() {
+                debug!("Retire client at end of keep-alive");
+                self.remove_client(i);
+                continue;
+            }
+            let client_id = client.get_client_id();
+            match client.poll_response(cx) {
+                Poll::Ready(Some(Ok((handle, response)))) => {
+                    if !self.sent.is_empty() {
+                        self.sent.remove(&handle.id());
+                    }
+                    self.observe_throttle(response.status(), response.headers());
+                    self.finish(Some(client_id), handle, Ok(response));
                     self.stats.current_stat.response_ok_count += 1;
-                    break;
+                }
+                Poll::Ready(Some(Err(Error::Unsolicited(status)))) => {
+                    info!("Retiring client after unsolicited {} response", status);
+                    self.remove_client(i);
+                    self.stats.current_stat.response_unsolicited_count += 1;
                 }
                 Poll::Ready(Some(Err(err))) => {
                     warn!("Encountered error: {:?}", err);
-                    self.client_section.clients.swap_remove(i);
+                    self.remove_client(i);
                     self.stats.current_stat.response_bad_count += 1;
                 }
                 Poll::Ready(None) => {
-                    self.client_section.clients.swap_remove(i);
+                    self.remove_client(i);
                 }
                 Poll::Pending => {
                     i += 1;
                 }
             }
         }
-        self.poll_connecting(cx);
+        self.poll_keep_alive(cx);
+        // Replacements for removed clients have to be polled once to register their wakeups.
         self.poll_maintain_connection();
+        self.poll_connecting(cx);
+        // Requests written now, such as replays from removed clients, are only flushed by the
+        // next poll.
+        let pending = self.pending_requests.len();
+        self.poll_send_request();
+        if self.pending_requests.len() != pending {
+            cx.waker().wake_by_ref();
+        }
+        if let Some(at) = self.limited_until.take() {
+            if self.limit_timer.poll_at(cx, at).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        self.poll_circuit(cx);
         self.record_status();
-        resp
+        if self.sink_waker.is_some() && self.can_take_request() {
+            self.sink_waker.take().unwrap().wake();
+        }
+        match self.finished.pop_front() {
+            Some(finished) => Poll::Ready(finished),
+            None => Poll::Pending,
+        }
+    }
+    /// Registers a wakeup for the earliest keep-alive expiry of the idle connections. Once it
+    /// passes, the next poll retires that connection and opens its replacement.
+    fn poll_keep_alive(&mut self, cx: &mut Context<'_>) {
+        let expiry = self
+            .client_section
+            .clients
+            .iter()
+            .filter(|x| x.accepts_requests())
+            .filter_map(|x| x.keep_alive_expiry())
+            .min();
+        if let Some(expiry) = expiry {
+            if self.keep_alive_timer.poll_at(cx, expiry).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+    }
+    /// Stops taking new requests. Requests already submitted still complete, after which the
+    /// pool's `Stream` ends and its connections are closed.
+    pub fn shutdown(&mut self) {
+        self.shutdown = true;
+    }
+    /// Whether a new request would go straight to a connection instead of waiting in the
+    /// pending queue.
+    fn can_take_request(&self) -> bool {
+        self.pending_requests.is_empty() && self.client_section.has_free_slot()
+    }
+    /// Whether no submitted request is still waiting to complete.
+    pub fn is_idle(&self) -> bool {
+        self.pending_requests.is_empty()
+            && self.finished.is_empty()
+            && self
+                .client_section
+                .clients
+                .iter()
+                .all(|x| x.queue_len() == 0)
     }
     pub fn get_status_records(&self) -> &HttpClientPoolStats {
         &self.stats
     }
 }
+
+impl<Channel, Buf, T> Stream for HttpClientPool<Channel, Buf, T>
+where
+    Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Buf: bytes::Buf + Clone + Unpin,
+    T: Clone + Unpin,
+{
+    type Item = Finished<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Poll::Ready(finished) = this.poll_response(cx) {
+            return Poll::Ready(Some(finished));
+        }
+        if this.shutdown && this.is_idle() {
+            this.client_section.clients.clear();
+            this.connecting.clear();
+            this.slots.clear();
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+/// Submits requests with backpressure: `poll_ready` waits until a connection has fewer than
+/// `max_conv_per_channel` requests in flight, the global rate limit budget has a token and no
+/// server pause is in effect. The slots free up as responses are read, so the pool's `Stream`
+/// side has to be polled as well.
+impl<Channel, Buf, T> Sink<(http::Request<Buf>, T)> for HttpClientPool<Channel, Buf, T>
+where
+    Channel: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Buf: bytes::Buf + Clone + Unpin,
+    T: Clone + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        let this = self.get_mut();
+        if this.shutdown {
+            return Poll::Ready(Err(Error::Cancelled));
+        }
+        this.poll_connecting(cx);
+        let now = timer::now();
+        let ready_at = match this.paused_until {
+            Some(until) => until.max(this.limiter.global_ready_at(now)),
+            None => this.limiter.global_ready_at(now),
+        };
+        if ready_at > now && this.sink_timer.poll_at(cx, ready_at).is_pending() {
+            return Poll::Pending;
+        }
+        if this.can_take_request() {
+            return Poll::Ready(Ok(()));
+        }
+        if this.usable_count() == 0 {
+            if this.circuit_state() == CircuitState::Open {
+                return Poll::Ready(Err(Error::CircuitOpen));
+            }
+            if this.connecting.is_empty() && this.make_connection() {
+                this.poll_connecting(cx);
+            }
+        }
+        this.poll_maintain_connection();
+        this.sink_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (http::Request<Buf>, T)) -> crate::Result<()> {
+        let (request, data) = item;
+        self.get_mut()
+            .request(request, data)
+            .map(drop)
+            .map_err(|x| x.error)
+    }
+
+    /// Flushes every connection's write buffer, and returns the first error if one failed.
+    /// The requests on the failed connection also fail on the `Stream` side.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        let mut flushed = true;
+        let mut error = None;
+        for client in &mut self.get_mut().client_section.clients {
+            match client.conn.poll_flush(cx) {
+                Poll::Ready(Err(err)) => {
+                    debug!("Flush failed: {}", err);
+                    error.get_or_insert(err);
+                }
+                Poll::Ready(Ok(())) => {}
+                Poll::Pending => flushed = false,
+            }
+        }
+        match error {
+            Some(err) => Poll::Ready(Err(Error::Io(err))),
+            None if flushed => Poll::Ready(Ok(())),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Flushes and shuts the pool down. The `Stream` still yields the outstanding responses.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        self.shutdown();
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::sync::mpsc;
+
+    fn get(path: &str) -> http::Request<Bytes> {
+        request(Method::GET, path)
+    }
+    fn request(method: Method, path: &str) -> http::Request<Bytes> {
+        http::Request::builder()
+            .method(method)
+            .uri(format!("http://example.com{}", path))
+            .body(Bytes::new())
+            .unwrap()
+    }
+    /// A pool whose connections are in-memory pipes. The server ends come out of the receiver.
+    fn duplex_pool(
+        config: HttpClientPoolConfig,
+    ) -> (
+        HttpClientPool<DuplexStream>,
+        mpsc::UnboundedReceiver<DuplexStream>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let pool = HttpClientPool::new(
+            move || {
+                let (client, server) = tokio::io::duplex(1 << 16);
+                let _ = tx.send(server);
+                async move { Ok(client) }
+            },
+            config,
+        );
+        (pool, rx)
+    }
+    /// Reads one bodiless request and returns its request line.
+    async fn read_request(server: &mut DuplexStream) -> String {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(server.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        head.lines().next().unwrap().to_string()
+    }
+    /// Answers `count` requests, with `ok` as the body except for HEAD requests, and returns
+    /// their request lines in the order they arrived.
+    fn serve(mut server: DuplexStream, count: usize) -> tokio::task::JoinHandle<Vec<String>> {
+        tokio::spawn(async move {
+            let mut seen = vec![];
+            for _ in 0..count {
+                let line = read_request(&mut server).await;
+                let response: &[u8] = if line.starts_with("HEAD") {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+                };
+                server.write_all(response).await.unwrap();
+                seen.push(line);
+            }
+            seen
+        })
+    }
+    async fn next_response<Buf: bytes::Buf + Clone>(
+        pool: &mut HttpClientPool<DuplexStream, Buf>,
+    ) -> Finished<()> {
+        futures::future::poll_fn(|cx| pool.poll_response(cx)).await
+    }
+
+    #[tokio::test]
+    async fn pending_request_fails_with_connect_error() {
+        let mut pool: HttpClientPool<DuplexStream> = HttpClientPool::new(
+            || async { Err(std::io::ErrorKind::ConnectionRefused.into()) },
+            Default::default(),
+        );
+        let handle = pool.request(get("/"), ()).unwrap();
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        match result {
+            Err(Error::Connect(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused)
+            }
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_request_times_out() {
+        let mut pool: HttpClientPool<DuplexStream> = HttpClientPool::new(
+            futures::future::pending,
+            HttpClientPoolConfig {
+                request_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
+        let handle = pool.request(get("/"), ()).unwrap();
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        assert!(matches!(result, Err(Error::Timeout)));
+        let stat = &pool.get_status_records().current_stat;
+        assert_eq!(stat.request_failed_count, 1);
+        assert_eq!(stat.request_expired_count, 0);
+    }
+
+    #[tokio::test]
+    async fn request_expires_while_waiting_for_a_connection() {
+        let (tx, mut servers) = mpsc::unbounded_channel();
+        let mut pool: HttpClientPool<DuplexStream> = HttpClientPool::new(
+            move || {
+                let (client, server) = tokio::io::duplex(1 << 16);
+                let _ = tx.send(server);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok(client)
+                }
+            },
+            Default::default(),
+        );
+        let options = RequestOptions {
+            ttl: Some(Duration::from_millis(30)),
+            ..Default::default()
+        };
+        let handle = pool.request_with_options(get("/"), (), options).unwrap();
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        assert!(matches!(result, Err(Error::Expired)));
+        assert_eq!(
+            pool.get_status_records().current_stat.request_expired_count,
+            1
+        );
+
+        // Once connected, the expired request is not written after all.
+        let mut server = servers.recv().await.unwrap();
+        let drive = futures::future::poll_fn(|cx| {
+            let _ = pool.poll_response(cx);
+            Poll::<()>::Pending
+        });
+        let mut buf = [0; 1];
+        tokio::select! {
+            _ = drive => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(200)) => {}
+            read = server.read(&mut buf) => panic!("server received {:?}", read),
+        }
+        assert_eq!(pool.client_section.clients.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn written_request_timeout_retires_its_connection() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            max_conv_per_channel: 4,
+            ..Default::default()
+        });
+        let options = RequestOptions {
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let a = pool.request_with_options(get("/a"), (), options).unwrap();
+        let b = pool.request(get("/b"), ()).unwrap();
+        let mut server = servers.recv().await.unwrap();
+        let server = tokio::spawn(async move {
+            read_request(&mut server).await;
+            read_request(&mut server).await;
+            server
+        });
+
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), a.id());
+        assert!(matches!(result, Err(Error::Timeout)));
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), b.id());
+        assert!(matches!(result, Err(Error::ConnectionReset)));
+        // The poisoned connection is gone and a replacement was opened.
+        assert!(pool.client_section.clients.iter().all(|x| !x.is_poisoned()));
+        assert!(servers.recv().await.is_some());
+        assert_eq!(pool.get_status_records().current_stat.response_bad_count, 1);
+        drop(server.await.unwrap());
+    }
+
+    /// Like `duplex_pool`, but connect attempts fail or succeed as `succeeds` says for each
+    /// attempt.
+    fn flaky_pool(
+        succeeds: impl Fn(usize) -> bool + Send + 'static,
+        config: HttpClientPoolConfig,
+    ) -> (
+        HttpClientPool<DuplexStream>,
+        mpsc::UnboundedReceiver<DuplexStream>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let pool = HttpClientPool::new(
+            move || {
+                let attempt = attempts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let result = if succeeds(attempt) {
+                    let (client, server) = tokio::io::duplex(1 << 16);
+                    let _ = tx.send(server);
+                    Ok(client)
+                } else {
+                    Err(std::io::ErrorKind::ConnectionRefused.into())
+                };
+                async move { result }
+            },
+            config,
+        );
+        (pool, rx)
+    }
+    fn short_backoff(maintain_size: Option<usize>) -> HttpClientPoolConfig {
+        HttpClientPoolConfig {
+            maintain_size,
+            connect: ConnectPolicy {
+                backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn ready_retries_failed_attempts() {
+        let (mut pool, _servers) = flaky_pool(|attempt| attempt >= 3, short_backoff(None));
+        let readiness = tokio::time::timeout(Duration::from_secs(2), pool.ready(1))
+            .await
+            .unwrap();
+        assert_eq!(readiness.connected, 1);
+        assert_eq!(readiness.failed_attempts, 3);
+        let stat = &pool.get_status_records().current_stat;
+        assert_eq!(stat.connection_failed_count, 3);
+        assert_eq!(stat.connection_living_count, 1);
+    }
+
+    #[tokio::test]
+    async fn connector_is_told_slot_attempt_previous_error_and_origin() {
+        type Call = (Option<Origin>, usize, u32, Option<String>);
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::<Call>::new()));
+        let recorded = calls.clone();
+        let (tx, _servers) = mpsc::unbounded_channel();
+        let origin = Origin::new(Scheme::HTTP, "example.com".parse().unwrap());
+        let mut pool: HttpClientPool<DuplexStream> = HttpClientPool::with_connector(
+            move |cx: &ConnectContext<'_>| {
+                let mut calls = recorded.lock().unwrap();
+                let failures = calls.len();
+                calls.push((
+                    cx.origin.cloned(),
+                    cx.slot,
+                    cx.attempt,
+                    cx.previous_error.map(|x| x.to_string()),
+                ));
+                // The first two attempts fail.
+                let result = if failures < 2 {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        format!("refused {}", failures),
+                    ))
+                } else {
+                    let (client, server) = tokio::io::duplex(1 << 16);
+                    let _ = tx.send(server);
+                    Ok(client)
+                };
+                async move { result }
+            },
+            HttpClientPoolConfig {
+                origin: Some(origin.clone()),
+                ..short_backoff(None)
+            },
+        );
+        let call = |slot, attempt, error: Option<&str>| {
+            (Some(origin.clone()), slot, attempt, error.map(String::from))
+        };
+        assert_eq!(pool.ready(1).await.connected, 1);
+        // Attempts count up across failures, reusing the slot the failed attempt held.
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                call(0, 1, None),
+                call(0, 2, Some("refused 0")),
+                call(0, 3, Some("refused 1")),
+            ]
+        );
+        // After a success they start over, in the next free slot.
+        assert_eq!(pool.ready(2).await.connected, 2);
+        assert_eq!(calls.lock().unwrap()[3], call(1, 1, None));
+        // A removed connection frees its slot for the next one.
+        let first = pool.slots.iter().find(|x| *x.1 == 0).map(|x| *x.0).unwrap();
+        let i = pool
+            .client_section
+            .clients
+            .iter()
+            .position(|x| x.get_client_id() == first)
+            .unwrap();
+        pool.remove_client(i);
+        assert_eq!(pool.ready(2).await.connected, 2);
+        assert_eq!(calls.lock().unwrap()[4], call(0, 1, None));
+        assert_eq!(calls.lock().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn wait_connected_gives_up_at_the_timeout() {
+        let (mut pool, _servers) = flaky_pool(|attempt| attempt == 0, short_backoff(Some(2)));
+        let started = Instant::now();
+        let readiness = pool.wait_connected(Duration::from_millis(100)).await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(readiness.connected, 1);
+        assert!(readiness.failed_attempts > 0);
+        assert_eq!(
+            readiness.failed_attempts as i64,
+            pool.get_status_records()
+                .current_stat
+                .connection_failed_count
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_during_backoff_connects_once_it_is_over() {
+        let (mut pool, mut servers) = flaky_pool(
+            |attempt| attempt > 0,
+            HttpClientPoolConfig {
+                connect: ConnectPolicy {
+                    backoff: Duration::from_secs(1),
+                    max_backoff: Duration::from_secs(1),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let started = tokio::time::Instant::now();
+        pool.request(get("/"), ()).unwrap();
+        let (_, result) = next_response(&mut pool).await;
+        assert!(matches!(result, Err(Error::Connect(_))));
+
+        let handle = pool.request(get("/"), ()).unwrap();
+        // The jitter shortens the backoff by up to half.
+        tokio::time::advance(Duration::from_millis(400)).await;
+        assert!(futures::poll!(futures::future::poll_fn(|cx| pool.poll_response(cx))).is_pending());
+        assert_eq!(
+            pool.get_status_records().current_stat.connection_new_count,
+            1
+        );
+        let server = tokio::spawn(async move { serve(servers.recv().await.unwrap(), 1).await });
+        let (finished, result) =
+            tokio::time::timeout(Duration::from_secs(5), next_response(&mut pool))
+                .await
+                .expect("no connection after the backoff");
+        assert_eq!(finished.id(), handle.id());
+        assert!(result.is_ok());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_secs(1), "{:?}", elapsed);
+        assert_eq!(
+            pool.get_status_records().current_stat.connection_new_count,
+            2
+        );
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn circuit_opens_half_opens_and_closes() {
+        let up = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let server_up = up.clone();
+        let (mut pool, _servers) = flaky_pool(
+            move |_| server_up.load(std::sync::atomic::Ordering::Relaxed),
+            HttpClientPoolConfig {
+                connect: ConnectPolicy {
+                    failure_threshold: 2,
+                    open_duration: Duration::from_secs(10),
+                    ..short_backoff(None).connect
+                },
+                ..Default::default()
+            },
+        );
+        let circuit = |pool: &HttpClientPool<DuplexStream>| {
+            (
+                pool.circuit_state(),
+                pool.get_status_records().current_stat.circuit_state,
+            )
+        };
+        let readiness = pool.wait_connected(Duration::from_secs(1)).await;
+        assert_eq!(readiness.failed_attempts, 2);
+        assert_eq!(circuit(&pool), (CircuitState::Open, 2));
+        // Requests fail fast while the circuit is open.
+        match pool.request(get("/"), ()) {
+            Err(rejected) => assert!(matches!(rejected.error, Error::CircuitOpen)),
+            Ok(_) => panic!("request accepted while the circuit is open"),
+        }
+
+        // A failed trial opens the circuit again.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(pool.circuit_state(), CircuitState::HalfOpen);
+        let readiness = pool.wait_connected(Duration::from_secs(1)).await;
+        assert_eq!(readiness.failed_attempts, 1);
+        assert_eq!(circuit(&pool), (CircuitState::Open, 2));
+
+        // A successful trial closes it.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        pool.record_status();
+        assert_eq!(circuit(&pool), (CircuitState::HalfOpen, 1));
+        up.store(true, std::sync::atomic::Ordering::Relaxed);
+        let readiness = pool.ready(1).await;
+        assert_eq!(readiness.connected, 1);
+        assert_eq!(circuit(&pool), (CircuitState::Closed, 0));
+        assert!(pool.request(get("/"), ()).is_ok());
+        // Reopening after the failed trial counts as well.
+        assert_eq!(
+            pool.get_status_records().current_stat.circuit_opened_count,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn head_request_waits_in_order_behind_other_methods() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            max_conv_per_channel: 4,
+            ..Default::default()
+        });
+        let handles: Vec<_> = vec![get("/a"), request(Method::HEAD, "/b"), get("/c")]
+            .into_iter()
+            .map(|x| pool.request(x, ()).unwrap().id())
+            .collect();
+        let server = serve(servers.recv().await.unwrap(), 3);
+        for expected in handles {
+            let (handle, result) = next_response(&mut pool).await;
+            assert_eq!(handle.id(), expected);
+            assert!(result.is_ok());
+        }
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "GET http://example.com/a HTTP/1.1",
+                "HEAD http://example.com/b HTTP/1.1",
+                "GET http://example.com/c HTTP/1.1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_replaced_before_keep_alive_timeout() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            ..Default::default()
+        });
+        pool.request(get("/"), ()).unwrap();
+        let mut server = servers.recv().await.unwrap();
+        let server = tokio::spawn(async move {
+            read_request(&mut server).await;
+            let response: &[u8] =
+                b"HTTP/1.1 200 OK\r\nKeep-Alive: timeout=1\r\nContent-Length: 0\r\n\r\n";
+            server.write_all(response).await.unwrap();
+            server
+        });
+        let started = Instant::now();
+        assert!(next_response(&mut pool).await.1.is_ok());
+        let server = server.await.unwrap();
+        // Nothing but the keep-alive expiry wakes the idle pool up.
+        let drive = futures::future::poll_fn(|cx| {
+            let _ = pool.poll_response(cx);
+            Poll::<()>::Pending
+        });
+        tokio::select! {
+            _ = drive => unreachable!(),
+            replacement = tokio::time::timeout(Duration::from_secs(2), servers.recv()) => {
+                assert!(matches!(replacement, Ok(Some(_))), "no replacement connection");
+            }
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(pool.client_section.clients.len(), 1);
+        drop(server);
+    }
+
+    fn poll_ready(pool: &mut HttpClientPool<DuplexStream>) -> Option<crate::Result<()>> {
+        futures::future::poll_fn(|cx| Pin::new(&mut *pool).poll_ready(cx)).now_or_never()
+    }
+
+    #[tokio::test]
+    async fn sink_waits_for_rate_limit() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            max_conv_per_channel: 4,
+            rate_limit: RateLimitConfig {
+                global: Some(crate::Budget {
+                    capacity: 1.0,
+                    per_second: 20.0,
+                }),
+                routes: vec![],
+            },
+            ..Default::default()
+        });
+        pool.ready(1).await;
+        assert!(matches!(poll_ready(&mut pool), Some(Ok(()))));
+        Pin::new(&mut pool).start_send((get("/"), ())).unwrap();
+        assert!(poll_ready(&mut pool).is_none());
+        let started = Instant::now();
+        futures::future::poll_fn(|cx| Pin::new(&mut pool).poll_ready(cx))
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        drop(servers.recv().await);
+    }
+
+    #[tokio::test]
+    async fn sink_waits_out_server_pause() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            max_conv_per_channel: 4,
+            throttle: ThrottlePolicy {
+                enabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        pool.request(get("/"), ()).unwrap();
+        let mut server = servers.recv().await.unwrap();
+        tokio::spawn(async move {
+            read_request(&mut server).await;
+            let response: &[u8] =
+                b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\n\r\n";
+            server.write_all(response).await.unwrap();
+            server
+        });
+        let (_, result) = next_response(&mut pool).await;
+        assert_eq!(
+            result.unwrap().status(),
+            http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!(poll_ready(&mut pool).is_none());
+    }
+
+    #[tokio::test]
+    async fn sink_flush_returns_write_error() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            ..Default::default()
+        });
+        pool.ready(1).await;
+        assert!(matches!(poll_ready(&mut pool), Some(Ok(()))));
+        drop(servers.recv().await);
+        Pin::new(&mut pool).start_send((get("/"), ())).unwrap();
+        let flushed = futures::future::poll_fn(|cx| Pin::new(&mut pool).poll_flush(cx)).await;
+        assert!(matches!(flushed, Err(Error::Io(_))));
+    }
+
+    /// Counts how often the task polling the pool is woken.
+    #[derive(Default)]
+    struct CountWakes(std::sync::atomic::AtomicUsize);
+    impl futures::task::ArcWake for CountWakes {
+        fn wake_by_ref(arc_self: &std::sync::Arc<Self>) {
+            arc_self
+                .0
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_pool_is_not_woken_without_timer_or_io() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            ..Default::default()
+        });
+        pool.ready(1).await;
+        let mut server = servers.recv().await.unwrap();
+        let wakes = std::sync::Arc::new(CountWakes::default());
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(pool.poll_response(&mut cx).is_pending());
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(wakes.0.load(std::sync::atomic::Ordering::Relaxed), 0);
+        // Data on the connection does wake it.
+        server
+            .write_all(b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(wakes.0.load(std::sync::atomic::Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn unsolicited_response_retires_the_connection() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            ..Default::default()
+        });
+        pool.ready(1).await;
+        let idle = pool.client_section.clients[0].get_client_id();
+        let mut server = servers.recv().await.unwrap();
+        server
+            .write_all(b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let replacement = tokio::select! {
+            _ = next_response(&mut pool) => unreachable!(),
+            replacement = servers.recv() => replacement,
+        };
+        assert!(replacement.is_some());
+        let stat = &pool.get_status_records().current_stat;
+        assert_eq!(stat.response_unsolicited_count, 1);
+        assert_eq!(stat.response_bad_count, 0);
+        assert!(pool
+            .client_section
+            .clients
+            .iter()
+            .all(|x| x.get_client_id() != idle));
+    }
+
+    #[tokio::test]
+    async fn stream_ends_after_shutdown_once_requests_finished() {
+        use futures::StreamExt;
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            ..Default::default()
+        });
+        let handle = pool.request(get("/"), ()).unwrap();
+        let mut server = servers.recv().await.unwrap();
+        // A pool that was not shut down keeps waiting for requests.
+        let request = tokio::spawn(async move {
+            read_request(&mut server).await;
+            server
+        });
+        assert!(futures::poll!(pool.next()).is_pending());
+        let mut server = request.await.unwrap();
+
+        pool.shutdown();
+        assert!(matches!(
+            pool.request(get("/"), ()),
+            Err(Rejected {
+                error: Error::Cancelled,
+                ..
+            })
+        ));
+        assert!(futures::poll!(pool.next()).is_pending());
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let (finished, result) = pool.next().await.unwrap();
+        assert_eq!(finished.id(), handle.id());
+        assert!(result.is_ok());
+        assert!(pool.next().await.is_none());
+        assert!(pool.client_section.clients.is_empty());
+    }
+
+    /// Answers every request on `server` after `delay`, until the connection closes.
+    fn serve_slowly(mut server: DuplexStream, delay: Duration) {
+        tokio::spawn(async move {
+            let mut head = vec![];
+            while let Ok(byte) = server.read_u8().await {
+                head.push(byte);
+                if head.ends_with(b"\r\n\r\n") {
+                    head.clear();
+                    tokio::time::sleep(delay).await;
+                    let response: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                    if server.write_all(response).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+    /// A pool of three connections, of which the one in slot 2 answers slowly, after every
+    /// connection completed two requests. Returns the client id of the slow one.
+    async fn pool_with_slow_connection(
+        prune: PrunePolicy,
+    ) -> (
+        HttpClientPool<DuplexStream>,
+        mpsc::UnboundedReceiver<DuplexStream>,
+        usize,
+    ) {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(3),
+            prune,
+            ..Default::default()
+        });
+        pool.ready(3).await;
+        for slot in 0..3 {
+            let delay = if slot == 2 { 40 } else { 0 };
+            serve_slowly(servers.recv().await.unwrap(), Duration::from_millis(delay));
+        }
+        for _ in 0..2 {
+            for _ in 0..3 {
+                pool.request(get("/"), ()).unwrap();
+            }
+            for _ in 0..3 {
+                assert!(next_response(&mut pool).await.1.is_ok());
+            }
+        }
+        let slow = pool.slots.iter().find(|x| *x.1 == 2).map(|x| *x.0).unwrap();
+        (pool, servers, slow)
+    }
+
+    #[tokio::test]
+    async fn connections_are_ranked_by_latency() {
+        let (mut pool, _servers, slow) = pool_with_slow_connection(PrunePolicy {
+            min_samples: 2,
+            ..Default::default()
+        })
+        .await;
+        let ranking = pool.rank_connections().to_vec();
+        assert_eq!(ranking.len(), 3);
+        assert!(ranking.windows(2).all(|x| x[0].latency <= x[1].latency));
+        assert_eq!(ranking[2].client_id, slow);
+        assert!(ranking[2].latency >= Duration::from_millis(40));
+        assert!(ranking.iter().all(|x| x.response_count == 2));
+        // Connections with fewer responses than `min_samples` are left out.
+        pool.config.prune.min_samples = 3;
+        assert!(pool.rank_connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn slowest_connection_is_retired_and_replaced() {
+        // A tenth of three connections still prunes one.
+        let (mut pool, mut servers, slow) = pool_with_slow_connection(PrunePolicy {
+            slowest_fraction: 0.1,
+            min_samples: 2,
+            ..Default::default()
+        })
+        .await;
+        // Pruning starts once every connection has its samples.
+        pool.config.prune.interval = Some(Duration::from_millis(50));
+        let drive = futures::future::poll_fn(|cx| {
+            let _ = pool.poll_response(cx);
+            Poll::<()>::Pending
+        });
+        tokio::select! {
+            _ = drive => unreachable!(),
+            replacement = tokio::time::timeout(Duration::from_secs(2), servers.recv()) => {
+                assert!(matches!(replacement, Ok(Some(_))), "no replacement connection");
+            }
+        }
+        assert_eq!(
+            pool.get_status_records()
+                .current_stat
+                .connection_pruned_count,
+            1
+        );
+        let clients = &pool.client_section.clients;
+        assert!(clients.iter().all(|x| x.get_client_id() != slow));
+    }
+
+    #[tokio::test]
+    async fn pending_hedged_original_keeps_its_connection_and_primary() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(2),
+            max_conv_per_channel: 4,
+            ..Default::default()
+        });
+        // No connection is up yet, so the original starts out pending.
+        let handle = pool
+            .request_hedged(get("/"), (), Hedge::Delay(Duration::from_millis(50)))
+            .unwrap();
+        // The connection that got the original answers once the copy reached the other one.
+        let servers = tokio::spawn(async move {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            for _ in 0..2 {
+                let mut server = servers.recv().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    read_request(&mut server).await;
+                    let _ = tx.send(server);
+                });
+            }
+            let mut original = rx.recv().await.unwrap();
+            let copy = rx.recv().await.unwrap();
+            original
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            (original, copy)
+        });
+        let (finished, result) =
+            tokio::time::timeout(Duration::from_secs(2), next_response(&mut pool))
+                .await
+                .expect("copy went to the original's connection");
+        assert_eq!(finished.id(), handle.id());
+        assert!(result.is_ok());
+        let stat = &pool.get_status_records().current_stat;
+        assert_eq!(stat.hedge_sent_count, 1);
+        assert_eq!(stat.hedge_loss_count, 1);
+        assert_eq!(stat.hedge_win_count, 0);
+        drop(servers.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn requests_for_other_origins_are_rejected() {
+        let (mut pool, _servers) = duplex_pool(HttpClientPoolConfig {
+            origin: Some(Origin::new(Scheme::HTTP, "example.com".parse().unwrap())),
+            ..Default::default()
+        });
+        for uri in &[
+            "http://other.com/",
+            "https://example.com/",
+            "http://example.com:81/",
+        ] {
+            let request = http::Request::get(*uri).body(Bytes::new()).unwrap();
+            match pool.request(request, ()) {
+                Err(rejected) => assert!(matches!(rejected.error, Error::InvalidRequest(_))),
+                Ok(_) => panic!("{} accepted", uri),
+            }
+        }
+        let host_elsewhere = http::Request::get("/")
+            .header(http::header::HOST, "other.com")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(pool.request(host_elsewhere, ()).is_err());
+        let accepted = vec![
+            get("/"),
+            http::Request::get("http://example.com:80/")
+                .body(Bytes::new())
+                .unwrap(),
+            http::Request::get("/").body(Bytes::new()).unwrap(),
+        ];
+        for request in accepted {
+            assert!(pool.request(request, ()).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_without_origin_serves_that_of_its_first_request() {
+        let other = || {
+            http::Request::get("http://other.com/")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let (mut pool, _servers) = duplex_pool(Default::default());
+        let first = http::Request::get("http://example.com/a")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(pool.request(first, ()).is_ok());
+        let example = Origin::new(Scheme::HTTP, "example.com".parse().unwrap());
+        assert_eq!(pool.config.origin, Some(example));
+        assert!(pool.request(other(), ()).is_err());
+        // Requests without an authority now go to the learned one.
+        assert!(pool.request(get("/b"), ()).is_ok());
+
+        // Without an authority in the first request, the default one is the origin.
+        let (mut pool, _servers) = duplex_pool(HttpClientPoolConfig {
+            client: HttpClientConfig {
+                default_authority: Some("default.com".parse().unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let bare = http::Request::get("/").body(Bytes::new()).unwrap();
+        assert!(pool.request(bare, ()).is_ok());
+        assert!(pool.request(other(), ()).is_err());
+        let default = http::Request::get("http://default.com/")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(pool.request(default, ()).is_ok());
+    }
+
+    /// A pool of one connection at a time, replaying idempotent requests once.
+    fn replaying_pool() -> (
+        HttpClientPool<DuplexStream>,
+        mpsc::UnboundedReceiver<DuplexStream>,
+    ) {
+        duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            retry: RetryPolicy { max_replays: 1 },
+            ..Default::default()
+        })
+    }
+    /// Reads one request, then closes the connection without answering.
+    fn reset_after_request(mut server: DuplexStream) {
+        tokio::spawn(async move {
+            read_request(&mut server).await;
+        });
+    }
+
+    #[tokio::test]
+    async fn idempotent_request_is_replayed_on_new_connection() {
+        let (mut pool, mut servers) = replaying_pool();
+        let handle = pool.request(get("/"), ()).unwrap();
+        reset_after_request(servers.recv().await.unwrap());
+        let replayed = tokio::spawn(async move {
+            let server = serve(servers.recv().await.unwrap(), 1);
+            server.await.unwrap()
+        });
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        assert_eq!(result.unwrap().body().as_ref(), b"ok");
+        assert_eq!(replayed.await.unwrap().len(), 1);
+        let stat = &pool.get_status_records().current_stat;
+        assert_eq!(stat.request_replayed_count, 1);
+    }
+
+    #[tokio::test]
+    async fn replay_opens_a_connection_without_maintain_size() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            retry: RetryPolicy { max_replays: 1 },
+            ..Default::default()
+        });
+        let handle = pool.request(get("/"), ()).unwrap();
+        reset_after_request(servers.recv().await.unwrap());
+        let replayed = tokio::spawn(async move {
+            let server = serve(servers.recv().await.unwrap(), 1);
+            server.await.unwrap()
+        });
+        let (finished, result) =
+            tokio::time::timeout(Duration::from_secs(2), next_response(&mut pool))
+                .await
+                .expect("replay never connected");
+        assert_eq!(finished.id(), handle.id());
+        assert_eq!(result.unwrap().body().as_ref(), b"ok");
+        assert_eq!(replayed.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_request_outcome_is_unknown() {
+        let (mut pool, mut servers) = replaying_pool();
+        let handle = pool.request(request(Method::POST, "/"), ()).unwrap();
+        reset_after_request(servers.recv().await.unwrap());
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        assert!(matches!(result, Err(Error::OutcomeUnknown)));
+    }
+
+    #[tokio::test]
+    async fn exhausted_replays_leave_outcome_unknown() {
+        let (mut pool, mut servers) = replaying_pool();
+        let handle = pool.request(get("/"), ()).unwrap();
+        reset_after_request(servers.recv().await.unwrap());
+        tokio::spawn(async move {
+            reset_after_request(servers.recv().await.unwrap());
+            // Keep the sender of later replacement connections alive.
+            while servers.recv().await.is_some() {}
+        });
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        assert!(matches!(result, Err(Error::OutcomeUnknown)));
+    }
+
+    #[tokio::test]
+    async fn unwritten_request_is_reported_never_written() {
+        let (mut pool, mut servers) = replaying_pool();
+        let handle = pool.request(request(Method::POST, "/"), ()).unwrap();
+        drop(servers.recv().await.unwrap());
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        assert!(matches!(result, Err(Error::NeverWritten)));
+    }
+
+    #[tokio::test]
+    async fn reset_without_retry_is_reported_as_reset() {
+        let (mut pool, mut servers) = duplex_pool(HttpClientPoolConfig {
+            maintain_size: Some(1),
+            ..Default::default()
+        });
+        let handle = pool.request(get("/"), ()).unwrap();
+        reset_after_request(servers.recv().await.unwrap());
+        let (finished, result) = next_response(&mut pool).await;
+        assert_eq!(finished.id(), handle.id());
+        assert!(matches!(result, Err(Error::ConnectionReset)));
+    }
+}
@@ -5,11 +5,28 @@ pub struct ConnectionStatistics {
     pub connection_new_count: i64,
     pub connection_living_count: i64,
     pub connection_connecting_count: i64,
+    pub connection_failed_count: i64,
+    pub connection_pruned_count: i64,
     pub request_initiated_count: i64,
     pub request_pending_count: i64,
     pub request_sent_count: i64,
     pub response_ok_count: i64,
     pub response_bad_count: i64,
+    pub request_failed_count: i64,
+    pub request_expired_count: i64,
+    pub response_unsolicited_count: i64,
+    pub request_replayed_count: i64,
+    /// 0 while the circuit is closed, 1 half open, 2 open.
+    pub circuit_state: i64,
+    pub circuit_opened_count: i64,
+    pub hedge_sent_count: i64,
+    pub hedge_win_count: i64,
+    pub hedge_loss_count: i64,
+    /// Tokens left in the global rate limit budget, -1 without one.
+    pub rate_limit_tokens: i64,
+    /// Tokens left in the emptiest route budget, -1 without any.
+    pub rate_limit_route_tokens: i64,
+    pub response_throttled_count: i64,
 }
 
 pub struct ConnectionStatisticsEntry {
@@ -19,25 +36,38 @@ pub struct ConnectionStatisticsEntry {
 
 impl ConnectionStatisticsEntry {
     pub fn write_csv_headers(mut write: impl Write) -> std::io::Result<()> {
-        writeln!(
-            write,
-            "{}, {}, {}, {}, {}, {}, {}, {}, {}",
+        let headers = [
             "time",
             "connection_new_count",
             "connection_living_count",
             "connection_connecting_count",
+            "connection_failed_count",
+            "connection_pruned_count",
             "request_initiated_count",
             "request_pending_count",
             "request_sent_count",
             "response_ok_count",
             "response_bad_count",
-        )?;
+            "request_failed_count",
+            "request_expired_count",
+            "response_unsolicited_count",
+            "request_replayed_count",
+            "circuit_state",
+            "circuit_opened_count",
+            "hedge_sent_count",
+            "hedge_win_count",
+            "hedge_loss_count",
+            "rate_limit_tokens",
+            "rate_limit_route_tokens",
+            "response_throttled_count",
+        ];
+        writeln!(write, "{}", headers.join(", "))?;
         Ok(())
     }
     pub fn write_csv_line(&self, mut write: impl Write) -> std::io::Result<()> {
         writeln!(
             write,
-            "{}, {}, {}, {}, {}, {}, {}, {}, {}",
+            "{}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}",
             self.time
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
@@ -45,11 +75,25 @@ impl ConnectionStatisticsEntry {
             self.stat.connection_new_count,
             self.stat.connection_living_count,
             self.stat.connection_connecting_count,
+            self.stat.connection_failed_count,
+            self.stat.connection_pruned_count,
             self.stat.request_initiated_count,
             self.stat.request_pending_count,
             self.stat.request_sent_count,
             self.stat.response_ok_count,
             self.stat.response_bad_count,
+            self.stat.request_failed_count,
+            self.stat.request_expired_count,
+            self.stat.response_unsolicited_count,
+            self.stat.request_replayed_count,
+            self.stat.circuit_state,
+            self.stat.circuit_opened_count,
+            self.stat.hedge_sent_count,
+            self.stat.hedge_win_count,
+            self.stat.hedge_loss_count,
+            self.stat.rate_limit_tokens,
+            self.stat.rate_limit_route_tokens,
+            self.stat.response_throttled_count,
         )?;
         Ok(())
     }
@@ -0,0 +1,276 @@
+use crate::{Connect, ConnectContext};
+use futures::future::BoxFuture;
+use http::uri::Scheme;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// TCP keepalive probing.
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    /// Idle time before the first probe.
+    pub time: Duration,
+    /// Time between probes. Not supported on every platform.
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped. Not supported on every platform.
+    pub retries: Option<u32>,
+}
+
+/// Connects over TCP with the socket options set on every connection. Connections are
+/// spread over the addresses the target resolves to by their slot in the pool.
+#[derive(Debug, Clone)]
+pub struct TcpConnector {
+    /// `host:port` to connect to. Without it, the origin from the connect context is used.
+    pub target: Option<String>,
+    /// `TCP_NODELAY`. On by default.
+    pub nodelay: bool,
+    /// `SO_RCVBUF`, set before connecting so that the window scale accounts for it.
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_SNDBUF`.
+    pub send_buffer_size: Option<usize>,
+    /// `TCP_QUICKACK`, Linux only. The kernel may clear it again, so it is only a hint for the
+    /// start of the connection.
+    pub quickack: bool,
+    /// `SO_BUSY_POLL` in microseconds, Linux only.
+    pub busy_poll: Option<u32>,
+    pub keepalive: Option<Keepalive>,
+    /// Local address and port to bind to before connecting.
+    pub local_addr: Option<SocketAddr>,
+    pub connect_timeout: Option<Duration>,
+}
+impl Default for TcpConnector {
+    fn default() -> Self {
+        Self {
+            target: None,
+            nodelay: true,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            quickack: false,
+            busy_poll: None,
+            keepalive: None,
+            local_addr: None,
+            connect_timeout: None,
+        }
+    }
+}
+impl TcpConnector {
+    pub fn new(target: impl Into<String>) -> Self {
+        Self {
+            target: Some(target.into()),
+            ..Default::default()
+        }
+    }
+    /// Connects to one of the addresses `target` resolves to, picked by `slot`.
+    pub async fn connect_to(&self, target: &str, slot: usize) -> io::Result<TcpStream> {
+        let addrs: Vec<_> = tokio::net::lookup_host(target).await?.collect();
+        let addr = pick_address(&addrs, slot).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} resolved to no address", target),
+            )
+        })?;
+        let connecting = self.socket(addr)?.connect(addr);
+        let stream = match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connecting)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??,
+            None => connecting.await?,
+        };
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.quickack {
+            socket2::SockRef::from(&stream).set_quickack(true)?;
+        }
+        Ok(stream)
+    }
+    fn socket(&self, addr: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_keepalive(true)?;
+            let params = socket2::TcpKeepalive::new().with_time(keepalive.time);
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+            let params = match keepalive.interval {
+                Some(interval) => params.with_interval(interval),
+                None => params,
+            };
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+            let params = match keepalive.retries {
+                Some(retries) => params.with_retries(retries),
+                None => params,
+            };
+            socket.set_tcp_keepalive(&params)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(micros) = self.busy_poll {
+            set_busy_poll(&socket, micros)?;
+        }
+        if let Some(local_addr) = self.local_addr {
+            socket.bind(&local_addr.into())?;
+        }
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+}
+
+/// Spreads the slots of a pool round-robin over the resolved addresses.
+fn pick_address(addrs: &[SocketAddr], slot: usize) -> Option<SocketAddr> {
+    if addrs.is_empty() {
+        return None;
+    }
+    Some(addrs[slot % addrs.len()])
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_busy_poll(socket: &Socket, micros: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let value = micros as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BUSY_POLL,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+impl Connect<TcpStream> for TcpConnector {
+    fn connect(&self, cx: &ConnectContext<'_>) -> BoxFuture<'static, io::Result<TcpStream>> {
+        let target = match (&self.target, cx.origin) {
+            (Some(target), _) => target.clone(),
+            (None, Some(origin)) => {
+                let default_port = if origin.scheme == Scheme::HTTPS {
+                    443
+                } else {
+                    80
+                };
+                let port = origin.authority.port_u16().unwrap_or(default_port);
+                format!("{}:{}", origin.authority.host(), port)
+            }
+            (None, None) => {
+                return Box::pin(async {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "no target to connect to",
+                    ))
+                })
+            }
+        };
+        let connector = self.clone();
+        let slot = cx.slot;
+        Box::pin(async move { connector.connect_to(&target, slot).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Origin;
+    use socket2::SockRef;
+    use tokio::net::TcpListener;
+
+    fn context(origin: Option<&Origin>, slot: usize) -> ConnectContext<'_> {
+        ConnectContext {
+            origin,
+            slot,
+            attempt: 1,
+            previous_error: None,
+        }
+    }
+
+    #[test]
+    fn slots_spread_over_addresses() {
+        let addrs: Vec<SocketAddr> =
+            vec!["127.0.0.1:1".parse().unwrap(), "[::1]:1".parse().unwrap()];
+        for (slot, expected) in [(0, 0), (1, 1), (2, 0), (5, 1)] {
+            assert_eq!(
+                pick_address(&addrs, slot),
+                Some(addrs[expected]),
+                "{}",
+                slot
+            );
+        }
+        assert_eq!(pick_address(&[], 0), None);
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_origin_without_a_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let origin = Origin::new(Scheme::HTTP, addr.to_string().parse().unwrap());
+        for slot in 0..3 {
+            let stream = TcpConnector::default()
+                .connect(&context(Some(&origin), slot))
+                .await
+                .unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), addr);
+            let (accepted, _) = listener.accept().await.unwrap();
+            assert_eq!(accepted.peer_addr().unwrap(), stream.local_addr().unwrap());
+        }
+        let err = TcpConnector::default()
+            .connect(&context(None, 0))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn target_takes_precedence_over_the_origin() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Nothing listens on the origin's port.
+        let origin = Origin::new(Scheme::HTTP, "127.0.0.1:1".parse().unwrap());
+        let stream = TcpConnector::new(addr.to_string())
+            .connect(&context(Some(&origin), 0))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn socket_options_are_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        for nodelay in [true, false] {
+            let connector = TcpConnector {
+                nodelay,
+                recv_buffer_size: Some(1 << 16),
+                send_buffer_size: Some(1 << 16),
+                keepalive: Some(Keepalive {
+                    time: Duration::from_secs(30),
+                    interval: Some(Duration::from_secs(5)),
+                    retries: Some(3),
+                }),
+                ..TcpConnector::new(target.clone())
+            };
+            let stream = connector.connect_to(&target, 0).await.unwrap();
+            let socket = SockRef::from(&stream);
+            assert_eq!(socket.nodelay().unwrap(), nodelay);
+            // The kernel may round the buffer sizes up, Linux doubles them.
+            assert!(socket.recv_buffer_size().unwrap() >= 1 << 16);
+            assert!(socket.send_buffer_size().unwrap() >= 1 << 16);
+            assert!(socket.keepalive().unwrap());
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+                assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+                assert_eq!(socket.keepalive_retries().unwrap(), 3);
+            }
+            listener.accept().await.unwrap();
+        }
+    }
+}
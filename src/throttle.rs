@@ -0,0 +1,259 @@
+use http::header::RETRY_AFTER;
+use http::{HeaderMap, StatusCode};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How the pool backs off when the server pushes back.
+#[derive(Debug, Clone)]
+pub struct ThrottlePolicy {
+    /// Pause dispatch on `429`, `503`, `Retry-After` and exhausted `X-RateLimit` budgets. Off
+    /// by default.
+    pub enabled: bool,
+    /// Pause after a `429` or `503` that does not say how long to wait.
+    pub default_pause: Duration,
+    /// Longest pause the server can ask for.
+    pub max_pause: Duration,
+}
+impl Default for ThrottlePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            default_pause: Duration::from_secs(1),
+            max_pause: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What a response says about the server's request budget.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    /// No request should be sent before this.
+    pub pause_until: Option<Instant>,
+    /// Requests left in the server's current window, from `X-RateLimit-Remaining`.
+    pub remaining: Option<u64>,
+}
+
+pub(crate) fn inspect(
+    status: StatusCode,
+    headers: &HeaderMap,
+    policy: &ThrottlePolicy,
+) -> Throttle {
+    let now = Instant::now();
+    let remaining = header_number(headers, "x-ratelimit-remaining");
+    let mut pause = headers
+        .get(RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(parse_retry_after);
+    if pause.is_none() && remaining == Some(0) {
+        pause = header_number(headers, "x-ratelimit-reset").map(reset_delay);
+    }
+    if pause.is_none()
+        && (status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE)
+    {
+        pause = Some(policy.default_pause);
+    }
+    Throttle {
+        pause_until: pause.map(|x| now + x.min(policy.max_pause)),
+        remaining,
+    }
+}
+
+fn header_number(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// `X-RateLimit-Reset` is either seconds until the reset, or the reset time as a Unix
+/// timestamp in seconds or milliseconds, depending on the server.
+fn reset_delay(value: u64) -> Duration {
+    let reset = if value >= 1_000_000_000_000 {
+        UNIX_EPOCH + Duration::from_millis(value)
+    } else if value >= 1_000_000_000 {
+        UNIX_EPOCH + Duration::from_secs(value)
+    } else {
+        return Duration::from_secs(value);
+    };
+    reset.duration_since(SystemTime::now()).unwrap_or_default()
+}
+
+/// Parses delay-seconds or an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let parts: Vec<_> = value.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let day: u64 = parts[1].parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|x| *x == parts[2])? as u64
+        + 1;
+    let year: u64 = parts[3].parse().ok()?;
+    let mut time = parts[4].split(':').map(|x| x.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    // Days since the epoch of a civil date, after Howard Hinnant's days_from_civil.
+    // Every step is checked, since the year and the time of day can be arbitrarily large.
+    let (y, m) = if month <= 2 {
+        (year.checked_sub(1)?, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let yoe = y % 400;
+    let doy = ((153 * m + 2) / 5).checked_add(day)?.checked_sub(1)?;
+    let doe = (yoe * 365 + yoe / 4 - yoe / 100).checked_add(doy)?;
+    let days = era
+        .checked_mul(146097)?
+        .checked_add(doe)?
+        .checked_sub(719468)?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(hour.checked_mul(3600)?)?
+        .checked_add(minute.checked_mul(60)?)?
+        .checked_add(second)?;
+    let date = UNIX_EPOCH.checked_add(Duration::from_secs(secs))?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+    fn assert_near(actual: Option<Duration>, expected: Duration) {
+        let actual = actual.expect("no delay");
+        let diff = actual.abs_diff(expected);
+        assert!(
+            diff <= Duration::from_secs(2),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn retry_after_delay_seconds() {
+        let cases = vec![
+            ("120", Some(Duration::from_secs(120))),
+            (" 5 ", Some(Duration::from_secs(5))),
+            ("0", Some(Duration::ZERO)),
+            ("-1", None),
+            ("1.5", None),
+            ("", None),
+            ("soon", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_retry_after(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        // 2100-01-01T00:00:00Z.
+        let expected = Duration::from_secs(4_102_444_800 - unix_now());
+        assert_near(parse_retry_after("Fri, 01 Jan 2100 00:00:00 GMT"), expected);
+        // 2100-03-01T12:30:15Z, past the end of February.
+        let expected = Duration::from_secs(4_107_587_415 - unix_now());
+        assert_near(parse_retry_after("Mon, 01 Mar 2100 12:30:15 GMT"), expected);
+        // Dates in the past mean no wait.
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::ZERO)
+        );
+        for malformed in [
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            // Out of range, rather than overflowing.
+            "Sun, 06 Nov 300000000000 08:49:37 GMT",
+            "Sun, 06 Nov 1994 99999999999999999:00:00 GMT",
+            "Sun, 06 Nov 18446744073709551615 00:00:00 GMT",
+            "Sun, 18446744073709551615 Nov 1994 00:00:00 GMT",
+        ]
+        .iter()
+        {
+            assert_eq!(parse_retry_after(malformed), None, "{:?}", malformed);
+        }
+    }
+
+    #[test]
+    fn rate_limit_reset_forms() {
+        assert_eq!(reset_delay(30), Duration::from_secs(30));
+        assert_near(Some(reset_delay(unix_now() + 60)), Duration::from_secs(60));
+        assert_near(
+            Some(reset_delay((unix_now() + 60) * 1000)),
+            Duration::from_secs(60),
+        );
+        assert_eq!(reset_delay(unix_now() - 60), Duration::ZERO);
+        assert_eq!(reset_delay((unix_now() - 60) * 1000), Duration::ZERO);
+    }
+
+    #[test]
+    fn inspect_responses() {
+        let policy = ThrottlePolicy {
+            enabled: true,
+            default_pause: Duration::from_secs(1),
+            max_pause: Duration::from_secs(60),
+        };
+        let secs = |x| Some(Duration::from_secs(x));
+        let cases: Vec<(u16, &[(&str, &str)], _, _)> = vec![
+            (200, &[], None, None),
+            (429, &[], secs(1), None),
+            (503, &[], secs(1), None),
+            (503, &[("retry-after", "7")], secs(7), None),
+            (200, &[("retry-after", "3")], secs(3), None),
+            (429, &[("retry-after", "1000")], secs(60), None),
+            (429, &[("retry-after", "later")], secs(1), None),
+            (
+                200,
+                &[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset", "12")],
+                secs(12),
+                Some(0),
+            ),
+            (
+                200,
+                &[("x-ratelimit-remaining", "5"), ("x-ratelimit-reset", "12")],
+                None,
+                Some(5),
+            ),
+            (
+                429,
+                &[
+                    ("retry-after", "2"),
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset", "12"),
+                ],
+                secs(2),
+                Some(0),
+            ),
+        ];
+        for (status, headers, pause, remaining) in cases {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, HeaderValue::from_static(value));
+            }
+            let before = Instant::now();
+            let throttle = inspect(StatusCode::from_u16(status).unwrap(), &map, &policy);
+            let after = Instant::now();
+            assert_eq!(throttle.remaining, remaining, "{} {:?}", status, headers);
+            match pause {
+                Some(pause) => {
+                    let until = throttle.pause_until.expect("no pause");
+                    assert!(before + pause <= until && until <= after + pause);
+                }
+                None => assert!(throttle.pause_until.is_none(), "{} {:?}", status, headers),
+            }
+        }
+    }
+}
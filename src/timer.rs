@@ -0,0 +1,30 @@
+use futures::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::time::Sleep;
+
+/// A single reusable sleep, re-armed to whatever deadline is polled next.
+#[derive(Default)]
+pub(crate) struct Timer {
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Timer {
+    /// Returns `Ready` once `deadline` has passed, otherwise registers a wakeup for it.
+    pub(crate) fn poll_at(&mut self, cx: &mut Context<'_>, deadline: Instant) -> Poll<()> {
+        let deadline = tokio::time::Instant::from_std(deadline);
+        match &mut self.sleep {
+            Some(sleep) if sleep.deadline() != deadline => sleep.as_mut().reset(deadline),
+            Some(_) => {}
+            None => self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline))),
+        }
+        self.sleep.as_mut().unwrap().as_mut().poll(cx)
+    }
+}
+
+/// The current time on tokio's clock. It is the system's monotonic clock, except that tests
+/// can pause and advance it.
+pub(crate) fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}